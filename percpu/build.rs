@@ -10,6 +10,11 @@ fn main() {
 
         let ld_script_path = Path::new(std::env!("CARGO_MANIFEST_DIR")).join(name);
         println!("cargo:rustc-link-arg-tests=-no-pie");
+        if cfg!(not(feature = "custom-tp")) {
+            // Since Rust 1.90, x86_64 Linux links with `rust-lld` by default, which rejects the `.percpu` section
+            // placed at address 0 by `test_percpu.x` as below the image base. GNU ld accepts it.
+            println!("cargo:rustc-link-arg-tests=-fuse-ld=bfd");
+        }
         println!("cargo:rustc-link-arg-tests=-T{}", ld_script_path.display());
     }
 }
@@ -0,0 +1,199 @@
+//! Per-CPU statistics counters.
+
+#[cfg(target_has_atomic = "64")]
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{alloc_percpu, PerCpu, PerCpuBox};
+
+macro_rules! def_counter {
+    ($(#[$attr:meta])* $name:ident, $int:ty, $atomic:ty) => {
+        $(#[$attr])*
+        pub struct $name {
+            local: PerCpuBox<$int>,
+            global: $atomic,
+            batch: $int,
+        }
+
+        impl $name {
+            /// Creates a counter with the local deltas never folded into the
+            /// shared count, i.e., [`sum`](Self::sum) always reads all CPUs.
+            ///
+            /// Returns `None` if there is not enough space in the dynamic area.
+            pub fn new() -> Option<Self> {
+                Self::with_batch(0)
+            }
+
+            /// Creates a counter whose local delta is folded into the shared
+            /// count once it reaches `batch`. A `batch` of 0 disables folding.
+            ///
+            /// Returns `None` if there is not enough space in the dynamic area.
+            pub fn with_batch(batch: $int) -> Option<Self> {
+                Some(Self {
+                    local: alloc_percpu(0)?,
+                    global: <$atomic>::new(0),
+                    batch,
+                })
+            }
+
+            /// Returns the local delta on the given CPU, or on the current CPU
+            /// if `cpu_id` is `None`.
+            fn local(&self, cpu_id: Option<usize>) -> &$atomic {
+                let ptr = match cpu_id {
+                    Some(cpu_id) => unsafe { self.local.remote_ptr(cpu_id) },
+                    None => unsafe { self.local.current_ptr() },
+                };
+                unsafe { &*(ptr as *const $atomic) }
+            }
+
+            /// Adds `val` to the counter on the current CPU (wrapping around
+            /// on overflow).
+            ///
+            /// The local delta is updated without a locked instruction where
+            /// possible: a `gs`-relative `add` on x86_64, or a restartable
+            /// sequence with the `rseq` feature. Other targets use a single
+            /// atomic instruction (e.g., `amoadd` on riscv64) or an atomic
+            /// loop. It does not disable IRQs, so it can be called in any
+            /// context. Folding the local delta into the shared count disables
+            /// preemption, so that it is folded on the CPU that owns it.
+            #[inline]
+            pub fn add(&self, val: $int) {
+                let offset = self.local.offset();
+                cfg_if::cfg_if! {
+                    if #[cfg(feature = "rseq")] {
+                        // threads on the same CPU may add to the local delta
+                        // between the two critical sections, so it is only
+                        // cleared if it is unchanged.
+                        let delta = unsafe { crate::rseq::fetch_add_current(offset, val) }
+                            .wrapping_add(val);
+                        if self.batch != 0
+                            && delta >= self.batch
+                            && unsafe { crate::rseq::cmpxchg_current(offset, delta, 0) }.is_ok()
+                        {
+                            self.global.fetch_add(delta, Ordering::Relaxed);
+                        }
+                    } else {
+                        cfg_if::cfg_if! {
+                            if #[cfg(all(
+                                target_arch = "x86_64",
+                                any(target_os = "linux", target_os = "none"),
+                                not(any(feature = "sp-naive", feature = "custom-tp", feature = "loom"))
+                            ))] {
+                                // a single instruction is not interrupted or
+                                // migrated halfway, so it needs no `lock`.
+                                unsafe {
+                                    core::arch::asm!(
+                                        "add gs:[{0}], {1}",
+                                        in(reg) offset,
+                                        in(reg) val,
+                                        options(nostack),
+                                    )
+                                };
+                            } else {
+                                // the local delta is still correct if the task
+                                // is migrated after taking the pointer, since
+                                // all CPUs update it atomically here.
+                                let _ = offset;
+                                self.local(None).fetch_add(val, Ordering::Relaxed);
+                            }
+                        }
+                        if self.batch != 0 {
+                            self.fold_local();
+                        }
+                    }
+                }
+            }
+
+            /// Folds the local delta on the current CPU into the shared count
+            /// if it reaches the batch.
+            #[cfg(not(feature = "rseq"))]
+            fn fold_local(&self) {
+                // the local delta may be updated without a locked instruction,
+                // so only its owner can clear it atomically. IRQ handlers on
+                // this CPU add to it with a single instruction, which does not
+                // interleave with the swap.
+                let _guard = crate::NoPreemptGuard::new();
+                let local = self.local(None);
+                if local.load(Ordering::Relaxed) >= self.batch {
+                    let delta = local.swap(0, Ordering::Relaxed);
+                    self.global.fetch_add(delta, Ordering::Relaxed);
+                }
+            }
+
+            /// Adds 1 to the counter on the current CPU.
+            #[inline]
+            pub fn inc(&self) {
+                self.add(1)
+            }
+
+            /// Returns the shared count, without the local deltas not folded
+            /// yet.
+            ///
+            /// It is cheap but may be behind [`sum`](Self::sum) by up to
+            /// `batch` on every CPU. It is always 0 if folding is disabled.
+            #[inline]
+            pub fn read(&self) -> $int {
+                self.global.load(Ordering::Relaxed)
+            }
+
+            /// Returns the sum of the shared count and the local deltas on all
+            /// CPUs.
+            ///
+            /// The local deltas are read one by one, so the result is
+            /// approximate if the counter is updated concurrently.
+            pub fn sum(&self) -> $int {
+                (0..crate::percpu_area_num()).fold(self.read(), |sum, cpu_id| {
+                    sum.wrapping_add(self.local(Some(cpu_id)).load(Ordering::Relaxed))
+                })
+            }
+
+            /// Resets the shared count and the local deltas on all CPUs to 0.
+            ///
+            /// Concurrent additions may or may not be kept.
+            pub fn reset_all(&self) {
+                self.global.store(0, Ordering::Relaxed);
+                for cpu_id in 0..crate::percpu_area_num() {
+                    self.local(Some(cpu_id)).store(0, Ordering::Relaxed);
+                }
+            }
+        }
+    };
+}
+
+def_counter!(
+    /// A `usize` statistics counter with fast local increments and
+    /// aggregated reads, like `percpu_counter` in Linux.
+    ///
+    /// Every CPU adds to its own delta in the dynamic per-CPU area, so
+    /// increments do not contend across CPUs. With a batch threshold, a local
+    /// delta is folded into a shared atomic count once it grows large enough,
+    /// so that [`read`](Self::read) gives an approximate value without visiting
+    /// all CPUs.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// percpu::init(1);
+    /// percpu::init_percpu_reg(0);
+    ///
+    /// let packets = percpu::PerCpuCounter::with_batch(32).unwrap();
+    /// packets.inc();
+    /// packets.add(2);
+    /// assert_eq!(packets.sum(), 3);
+    /// ```
+    PerCpuCounter,
+    usize,
+    AtomicUsize
+);
+
+#[cfg(target_has_atomic = "64")]
+def_counter!(
+    /// A `u64` statistics counter with fast local increments and aggregated
+    /// reads, which does not overflow as fast as [`PerCpuCounter`] on 32-bit
+    /// targets.
+    ///
+    /// See [`PerCpuCounter`] for details.
+    PerCpuCounter64,
+    u64,
+    AtomicU64
+);
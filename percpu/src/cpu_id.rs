@@ -0,0 +1,55 @@
+//! The built-in per-CPU slot holding the ID of each CPU.
+
+use crate::{percpu_area_base, percpu_area_num};
+
+/// The ID of the CPU that owns the per-CPU data area, written by
+/// `init_percpu_reg`.
+#[cfg(not(feature = "rseq"))]
+#[crate::def_percpu]
+pub(crate) static CPU_ID: usize = 0;
+
+/// Records `cpu_id` as the ID of the current CPU.
+///
+/// # Safety
+///
+/// Caller must ensure that the per-CPU data register points to the area of
+/// the given CPU.
+#[cfg(not(any(feature = "sp-naive", feature = "rseq")))]
+pub(crate) unsafe fn init_current_cpu_id(cpu_id: usize) {
+    // written through the pointer, so that it is not tracked as a data access
+    // by loom when several threads are bound to the same CPU.
+    *(crate::PerCpu::current_ptr(&CPU_ID) as *mut usize) = cpu_id
+}
+
+/// Returns the ID of the current CPU, as passed to `init_percpu_reg`.
+///
+/// It is read from a built-in per-CPU variable with a single load relative to
+/// the per-CPU data register (except for the `custom-tp` backend), so it is as
+/// cheap as reading a user-defined `CPU_ID` variable. The per-CPU data
+/// register must be initialized by `init_percpu_reg` rather than written
+/// directly with `write_percpu_reg`. It is always 0 for "sp-naive" use.
+///
+/// If preemption is enabled, the task may be migrated to another CPU right
+/// after the call, so the result should be used under a preemption guard (e.g.,
+/// in [`scope`](crate::scope)) if it matters.
+///
+/// With the `rseq` feature, it is the CPU that the thread is running on, read
+/// from the rseq area.
+#[inline]
+pub fn this_cpu_id() -> usize {
+    #[cfg(feature = "rseq")]
+    return crate::rseq::current_cpu();
+    #[cfg(not(feature = "rseq"))]
+    unsafe {
+        CPU_ID.read_current_raw()
+    }
+}
+
+/// Returns the ID of the CPU whose per-CPU data area starts at `base`, i.e.,
+/// the inverse of `percpu_area_base`.
+///
+/// Returns `None` if `base` is not the base address of any per-CPU data area.
+pub fn cpu_id_of_area(base: usize) -> Option<usize> {
+    // The areas may be allocated separately, so they are not always in order.
+    (0..percpu_area_num()).find(|&cpu_id| percpu_area_base(cpu_id) == base)
+}
@@ -0,0 +1,159 @@
+//! Dynamic per-CPU allocation.
+//!
+//! A chunk of [`DYNAMIC_AREA_SIZE`] bytes is reserved in every per-CPU data
+//! area, as a per-CPU static variable. [`alloc_percpu`] allocates a slot at the
+//! same offset in the chunk of every CPU, so it can be accessed relative to the
+//! per-CPU data register just like static per-CPU variables.
+
+use core::{marker::PhantomData, mem, ptr};
+
+use spin::Mutex;
+
+use crate::{percpu_area_num, PerCpu};
+
+/// Size in bytes of the chunk reserved in every per-CPU data area for dynamic
+/// allocation.
+///
+/// It is added to the size of every per-CPU data area whether it is used or
+/// not, i.e., `DYNAMIC_AREA_SIZE * cpu_count` bytes in total.
+pub const DYNAMIC_AREA_SIZE: usize = 0x1000;
+
+/// The allocation granularity and the maximum supported alignment.
+const GRANULE_SIZE: usize = 8;
+const MAX_ALIGN: usize = 64;
+const GRANULE_NUM: usize = DYNAMIC_AREA_SIZE / GRANULE_SIZE;
+
+#[repr(C, align(64))]
+struct DynamicArea([u8; DYNAMIC_AREA_SIZE]);
+
+#[crate::def_percpu]
+static DYNAMIC_AREA: DynamicArea = DynamicArea([0; DYNAMIC_AREA_SIZE]);
+
+/// Returns the range of the dynamic area relative to the per-CPU data area
+/// base.
+pub(crate) fn dynamic_area_range() -> core::ops::Range<usize> {
+    let start = DYNAMIC_AREA.offset();
+    start..start + DYNAMIC_AREA_SIZE
+}
+
+/// Bitmap of allocated granules in the dynamic area, shared by all CPUs.
+static ALLOCATOR: Mutex<[u64; GRANULE_NUM / 64]> = Mutex::new([0; GRANULE_NUM / 64]);
+
+fn is_free(bitmap: &[u64], start: usize, num: usize) -> bool {
+    (start..start + num).all(|i| bitmap[i / 64] & (1 << (i % 64)) == 0)
+}
+
+fn set_range(bitmap: &mut [u64], start: usize, num: usize, used: bool) {
+    for i in start..start + num {
+        if used {
+            bitmap[i / 64] |= 1 << (i % 64);
+        } else {
+            bitmap[i / 64] &= !(1 << (i % 64));
+        }
+    }
+}
+
+/// Allocates `size` bytes aligned to `align` in the dynamic area, returns the
+/// offset relative to the area base.
+fn alloc_slot(size: usize, align: usize) -> Option<usize> {
+    if align > MAX_ALIGN {
+        return None;
+    }
+    let num = (size.max(1) + GRANULE_SIZE - 1) / GRANULE_SIZE;
+    let step = align.max(GRANULE_SIZE) / GRANULE_SIZE;
+    let mut bitmap = ALLOCATOR.lock();
+    let start = (0..GRANULE_NUM.checked_sub(num)? + 1)
+        .step_by(step)
+        .find(|&start| is_free(&*bitmap, start, num))?;
+    set_range(&mut *bitmap, start, num, true);
+    Some(start * GRANULE_SIZE)
+}
+
+fn dealloc_slot(offset: usize, size: usize) {
+    let num = (size.max(1) + GRANULE_SIZE - 1) / GRANULE_SIZE;
+    set_range(&mut *ALLOCATOR.lock(), offset / GRANULE_SIZE, num, false);
+}
+
+/// Allocates a dynamic per-CPU variable, initialized to a clone of `init` on
+/// every CPU.
+///
+/// Returns `None` if there is not enough space in the dynamic area, or the
+/// alignment of `T` is larger than 64 bytes.
+///
+/// # Panics
+///
+/// Panics if the per-CPU data areas have not been initialized by
+/// [`init`](crate::init), since initialization overwrites the areas of the
+/// secondary CPUs.
+///
+/// # Examples
+///
+/// ```no_run
+/// use percpu::PerCpu;
+///
+/// percpu::init(1);
+/// percpu::init_percpu_reg(0);
+///
+/// let counter = percpu::alloc_percpu(0usize).unwrap();
+/// counter.with_current(|c| *c += 1);
+/// assert_eq!(counter.read_remote(0), 1);
+/// ```
+pub fn alloc_percpu<T: Clone>(init: T) -> Option<PerCpuBox<T>> {
+    assert!(
+        crate::imp::is_init(),
+        "per-CPU data areas must be initialized before `alloc_percpu`"
+    );
+    let slot = alloc_slot(mem::size_of::<T>(), mem::align_of::<T>())?;
+    let percpu = PerCpuBox {
+        slot,
+        _marker: PhantomData,
+    };
+    for cpu_id in 0..percpu_area_num() {
+        unsafe { ptr::write(percpu.remote_ptr(cpu_id) as *mut T, init.clone()) };
+    }
+    Some(percpu)
+}
+
+/// An owned dynamic per-CPU variable, allocated by [`alloc_percpu`].
+///
+/// It is accessed through the [`PerCpu`] trait, like static per-CPU variables.
+/// When it is dropped, the values on all CPUs are dropped and the slot is
+/// freed.
+pub struct PerCpuBox<T> {
+    slot: usize,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for PerCpuBox<T> {}
+unsafe impl<T: Send> Sync for PerCpuBox<T> {}
+
+impl<T> PerCpu for PerCpuBox<T> {
+    type Target = T;
+    type Guard = crate::NoPreemptGuard;
+
+    #[inline]
+    fn offset(&self) -> usize {
+        DYNAMIC_AREA.offset() + self.slot
+    }
+
+    #[inline]
+    unsafe fn current_ptr(&self) -> *const T {
+        (DYNAMIC_AREA.current_ptr() as usize + self.slot) as *const T
+    }
+
+    #[inline]
+    unsafe fn remote_ptr(&self, cpu_id: usize) -> *const T {
+        // the slot is only initialized on the CPUs that `alloc_percpu` saw.
+        assert!(cpu_id < percpu_area_num(), "invalid CPU ID: {}", cpu_id);
+        (DYNAMIC_AREA.remote_ptr(cpu_id) as usize + self.slot) as *const T
+    }
+}
+
+impl<T> Drop for PerCpuBox<T> {
+    fn drop(&mut self) {
+        for cpu_id in 0..percpu_area_num() {
+            unsafe { ptr::drop_in_place(self.remote_ptr(cpu_id) as *mut T) };
+        }
+        dealloc_slot(self.slot, mem::size_of::<T>());
+    }
+}
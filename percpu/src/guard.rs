@@ -0,0 +1,342 @@
+//! Guards that keep preemption (and optionally local IRQs) disabled while
+//! referencing per-CPU data.
+
+use core::{
+    fmt::{Debug, Display},
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+use kernel_guard::BaseGuard;
+
+use crate::PerCpu;
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "preempt")] {
+        /// The guard used by the default per-CPU accessors, which disables
+        /// preemption if the `preempt` feature is enabled, and does nothing
+        /// otherwise.
+        pub type NoPreemptGuard = kernel_guard::NoPreempt;
+
+        /// The guard used by the `*_irqsave` per-CPU accessors, which disables
+        /// local IRQs, as well as preemption if the `preempt` feature is
+        /// enabled.
+        pub type IrqSaveGuard = kernel_guard::NoPreemptIrqSave;
+    } else {
+        /// The guard used by the default per-CPU accessors, which disables
+        /// preemption if the `preempt` feature is enabled, and does nothing
+        /// otherwise.
+        pub type NoPreemptGuard = kernel_guard::NoOp;
+
+        /// The guard used by the `*_irqsave` per-CPU accessors, which disables
+        /// local IRQs, as well as preemption if the `preempt` feature is
+        /// enabled.
+        pub type IrqSaveGuard = kernel_guard::IrqSave;
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+
+    impl Sealed for super::NoPreemptGuard {}
+    // both are `kernel_guard::NoOp` on hosted targets.
+    #[cfg(target_os = "none")]
+    impl Sealed for super::IrqSaveGuard {}
+}
+
+/// A guard that per-CPU data on the current CPU can be referenced under, i.e.,
+/// [`NoPreemptGuard`] or [`IrqSaveGuard`].
+///
+/// It is sealed, so that the per-CPU data cannot be referenced under a guard
+/// that keeps preemption enabled.
+pub trait PerCpuGuard: BaseGuard + sealed::Sealed {}
+
+impl PerCpuGuard for NoPreemptGuard {}
+#[cfg(target_os = "none")]
+impl PerCpuGuard for IrqSaveGuard {}
+
+/// A proof that preemption is disabled on the current CPU, which is passed to
+/// the closure of [`scope`] or [`scope_irqsave`], or borrowed from a
+/// [`CpuGuard`].
+///
+/// It is only available by reference and cannot be sent to other threads, so
+/// per-CPU data accessed through it with `get` or `get_mut` always belongs to
+/// the same CPU.
+pub struct NoPreemptToken {
+    _not_send: PhantomData<*const ()>,
+}
+
+impl NoPreemptToken {
+    const fn new() -> Self {
+        Self {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+/// A guard that disables preemption (and local IRQs if `G` is
+/// [`IrqSaveGuard`]) until it is dropped, and dereferences to a
+/// [`NoPreemptToken`].
+///
+/// With the token, per-CPU data on the current CPU can be read safely through
+/// `get` and `read`, instead of the unsafe `*_raw` accessors. `get_mut` and
+/// `write` borrow the token mutably, so the borrow checker ensures that no
+/// other reference obtained through the guard is alive. They are still unsafe,
+/// since another token (of a nested [`scope`] or another `CpuGuard`) or
+/// `with_current` can reference the same data meanwhile. It has no overhead if
+/// the `preempt` feature is disabled.
+///
+/// # Examples
+///
+/// ```no_run
+/// #[percpu::def_percpu]
+/// static FOO: usize = 0;
+///
+/// let mut cpu = percpu::CpuGuard::new();
+/// let foo = FOO.read(&cpu);
+/// // no other reference to `FOO` is alive.
+/// unsafe { FOO.write(&mut cpu, foo + 1) };
+/// drop(cpu); // FOO is no longer accessible through `cpu`.
+/// ```
+pub struct CpuGuard<G: PerCpuGuard = NoPreemptGuard> {
+    state: G::State,
+    token: NoPreemptToken,
+}
+
+impl<G: PerCpuGuard> CpuGuard<G> {
+    /// Enters the critical section of `G` until the guard is dropped. It is
+    /// used by the default methods of [`PerCpu`](crate::PerCpu), which are
+    /// generic over [`PerCpu::Guard`](crate::PerCpu::Guard).
+    #[inline]
+    pub(crate) fn enter() -> Self {
+        Self {
+            state: G::acquire(),
+            token: NoPreemptToken::new(),
+        }
+    }
+}
+
+impl CpuGuard<NoPreemptGuard> {
+    /// Disables preemption until the guard is dropped.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self::enter()
+    }
+}
+
+impl CpuGuard<IrqSaveGuard> {
+    /// Disables local IRQs and preemption until the guard is dropped.
+    pub fn new_irqsave() -> Self {
+        Self::enter()
+    }
+}
+
+impl<G: PerCpuGuard> Deref for CpuGuard<G> {
+    type Target = NoPreemptToken;
+
+    #[inline]
+    fn deref(&self) -> &NoPreemptToken {
+        &self.token
+    }
+}
+
+impl<G: PerCpuGuard> DerefMut for CpuGuard<G> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut NoPreemptToken {
+        &mut self.token
+    }
+}
+
+impl<G: PerCpuGuard> Drop for CpuGuard<G> {
+    #[inline]
+    fn drop(&mut self) {
+        G::release(self.state);
+    }
+}
+
+/// Disables preemption once and calls `f` with a [`NoPreemptToken`], through
+/// which multiple per-CPU variables can be accessed without additional
+/// guards.
+///
+/// The token is passed by mutable reference, so that a reference returned by
+/// `get_mut` borrows it exclusively. A nested `scope` creates another token,
+/// so `get_mut` is unsafe and its caller must ensure that no reference
+/// obtained through the other token is alive.
+///
+/// # Examples
+///
+/// ```no_run
+/// #[percpu::def_percpu]
+/// static FOO: usize = 0;
+/// #[percpu::def_percpu]
+/// static BAR: usize = 0;
+///
+/// percpu::scope(|cpu| unsafe {
+///     *FOO.get_mut(cpu) += 1;
+///     *BAR.get_mut(cpu) = *FOO.get(cpu); // both from the same CPU
+/// });
+/// ```
+///
+/// Two mutable references to the same per-CPU data cannot be obtained from
+/// one token:
+///
+/// ```compile_fail,E0499
+/// # #[percpu::def_percpu]
+/// # static FOO: usize = 0;
+/// percpu::scope(|cpu| unsafe {
+///     let a = FOO.get_mut(cpu);
+///     let b = FOO.get_mut(cpu); // error: `*cpu` is already borrowed
+///     *a += *b;
+/// });
+/// ```
+///
+/// The token of a nested scope cannot be used to mutate per-CPU data that is
+/// still referenced through the outer token in safe code:
+///
+/// ```compile_fail,E0133
+/// # #[percpu::def_percpu]
+/// # static FOO: usize = 0;
+/// percpu::scope(|outer| {
+///     let a = FOO.get(outer);
+///     percpu::scope(|inner| *FOO.get_mut(inner) += 1); // error: `get_mut` is unsafe
+///     assert_eq!(*a, 0);
+/// });
+/// ```
+pub fn scope<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut NoPreemptToken) -> R,
+{
+    let _guard = NoPreemptGuard::new();
+    f(&mut NoPreemptToken::new())
+}
+
+/// Like [`scope`], but disables local IRQs as well as preemption.
+pub fn scope_irqsave<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut NoPreemptToken) -> R,
+{
+    let _guard = IrqSaveGuard::new();
+    f(&mut NoPreemptToken::new())
+}
+
+/// A shared reference to the per-CPU data on the current CPU, returned by
+/// `current()` or `current_irqsave()`.
+///
+/// The critical section of `G` (e.g., preemption disabled) lasts until the
+/// guard is dropped, so the referenced data always belongs to the CPU that the
+/// guard was created on. The guard cannot be sent to other threads.
+pub struct PerCpuRef<'a, T, G: PerCpuGuard = NoPreemptGuard> {
+    value: &'a T,
+    state: G::State,
+    _not_send: PhantomData<*const ()>,
+}
+
+/// A mutable reference to the per-CPU data on the current CPU, returned by
+/// `current_mut()` or `current_mut_irqsave()`.
+///
+/// The critical section of `G` (e.g., preemption disabled) lasts until the
+/// guard is dropped, so the referenced data always belongs to the CPU that the
+/// guard was created on. The guard cannot be sent to other threads.
+///
+/// Nothing prevents another reference to the same per-CPU data from being
+/// created while the guard is held, so it is created by unsafe functions. Use
+/// `with_current` to mutate per-CPU data in safe code.
+pub struct PerCpuRefMut<'a, T, G: PerCpuGuard = NoPreemptGuard> {
+    value: &'a mut T,
+    state: G::State,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a, T, G: PerCpuGuard> PerCpuRef<'a, T, G> {
+    /// Enters the critical section of `G` and references the given per-CPU
+    /// variable on the current CPU.
+    pub fn new<P: PerCpu<Target = T> + ?Sized>(var: &'a P) -> Self {
+        let state = G::acquire();
+        Self {
+            value: unsafe { var.current_ref_raw() },
+            state,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<'a, T, G: PerCpuGuard> PerCpuRefMut<'a, T, G> {
+    /// Enters the critical section of `G` and mutably references the given
+    /// per-CPU variable on the current CPU.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that no other reference to the per-CPU variable on
+    /// the current CPU is alive while the guard is held.
+    pub unsafe fn new<P: PerCpu<Target = T> + ?Sized>(var: &'a P) -> Self {
+        let state = G::acquire();
+        Self {
+            value: unsafe { var.current_ref_mut_raw() },
+            state,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T, G: PerCpuGuard> Drop for PerCpuRef<'_, T, G> {
+    #[inline]
+    fn drop(&mut self) {
+        G::release(self.state);
+    }
+}
+
+impl<T, G: PerCpuGuard> Drop for PerCpuRefMut<'_, T, G> {
+    #[inline]
+    fn drop(&mut self) {
+        G::release(self.state);
+    }
+}
+
+impl<T, G: PerCpuGuard> Deref for PerCpuRef<'_, T, G> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T, G: PerCpuGuard> Deref for PerCpuRefMut<'_, T, G> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T, G: PerCpuGuard> DerefMut for PerCpuRefMut<'_, T, G> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+impl<T: Debug, G: PerCpuGuard> Debug for PerCpuRef<'_, T, G> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+impl<T: Display, G: PerCpuGuard> Display for PerCpuRef<'_, T, G> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+impl<T: Debug, G: PerCpuGuard> Debug for PerCpuRefMut<'_, T, G> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+impl<T: Display, G: PerCpuGuard> Display for PerCpuRefMut<'_, T, G> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.value.fmt(f)
+    }
+}
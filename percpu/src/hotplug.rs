@@ -0,0 +1,199 @@
+//! CPU hotplug support for per-CPU data areas.
+//!
+//! All CPUs are online after the per-CPU data areas are initialized. A CPU can
+//! be taken offline with [`cpu_offline`] and brought back with [`cpu_online`],
+//! which optionally resets its area to the link-time template. Hooks
+//! registered with [`register_hotplug_hook`] are called on each transition, so
+//! that per-CPU variables can be drained or re-initialized.
+//!
+//! Transitions are serialized by a spin lock, which is held while the hooks are
+//! called. Hooks must not block or sleep, since other CPUs spin while waiting
+//! for a transition. They may register other hooks, but must not call
+//! [`cpu_offline`] or [`cpu_online`], which would deadlock.
+
+use core::{
+    fmt,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::percpu_area_num;
+
+/// Whether the CPU is offline. It is `false` in the template, so all CPUs are
+/// online after initialization.
+///
+/// It is atomic since [`is_cpu_online`] reads it without holding the lock.
+#[crate::def_percpu]
+pub(crate) static CPU_OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Serializes hotplug transitions.
+static HOTPLUG_LOCK: Mutex<()> = Mutex::new(());
+
+/// The head of the intrusive list of registered hooks. Hooks are only
+/// prepended, so it can be traversed and extended without the lock.
+static HOOKS: AtomicPtr<HotplugHook> = AtomicPtr::new(ptr::null_mut());
+
+/// Errors returned by CPU hotplug operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotplugError {
+    /// The CPU ID is not less than [`percpu_area_num`].
+    InvalidCpuId,
+    /// The CPU is already online.
+    AlreadyOnline,
+    /// The CPU is already offline.
+    AlreadyOffline,
+    /// The per-CPU data template is not available to reset the area, since it
+    /// is overlapped by the per-CPU data areas and no copy of it is kept.
+    TemplateUnavailable,
+}
+
+impl fmt::Display for HotplugError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidCpuId => write!(f, "invalid CPU ID"),
+            Self::AlreadyOnline => write!(f, "CPU is already online"),
+            Self::AlreadyOffline => write!(f, "CPU is already offline"),
+            Self::TemplateUnavailable => write!(f, "per-CPU data template is unavailable"),
+        }
+    }
+}
+
+/// Callbacks invoked when a CPU goes online or offline.
+///
+/// Hooks are registered once with [`register_hotplug_hook`] and live forever,
+/// so they are usually defined as `static` items.
+///
+/// The callbacks are called with the hotplug lock held, which is a spin lock,
+/// so they must not block or sleep, and must not call [`cpu_offline`] or
+/// [`cpu_online`].
+///
+/// # Examples
+///
+/// ```no_run
+/// use percpu::HotplugHook;
+///
+/// #[percpu::def_percpu]
+/// static PENDING: usize = 0;
+///
+/// static PENDING_HOOK: HotplugHook = HotplugHook::new(None, Some(|cpu_id| {
+///     // drain the pending work of the CPU going offline.
+///     unsafe { *PENDING.remote_ref_mut_raw(cpu_id) = 0 };
+/// }));
+///
+/// percpu::register_hotplug_hook(&PENDING_HOOK);
+/// ```
+pub struct HotplugHook {
+    online: Option<fn(usize)>,
+    offline: Option<fn(usize)>,
+    registered: AtomicBool,
+    next: AtomicPtr<HotplugHook>,
+}
+
+impl HotplugHook {
+    /// Creates a hook with the callbacks invoked after a CPU goes online and
+    /// before a CPU goes offline, with the CPU ID as the argument.
+    pub const fn new(online: Option<fn(usize)>, offline: Option<fn(usize)>) -> Self {
+        Self {
+            online,
+            offline,
+            registered: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+}
+
+/// Registers a hook called on CPU hotplug transitions.
+///
+/// Hooks are called in the reverse order of registration. Registering the
+/// same hook twice has no effect.
+///
+/// It does not take the hotplug lock, so it can be called from a hook. A hook
+/// registered during a transition may or may not be called for it.
+pub fn register_hotplug_hook(hook: &'static HotplugHook) {
+    if hook.registered.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    let hook_ptr = hook as *const HotplugHook as *mut HotplugHook;
+    let mut head = HOOKS.load(Ordering::Acquire);
+    loop {
+        hook.next.store(head, Ordering::Relaxed);
+        match HOOKS.compare_exchange_weak(head, hook_ptr, Ordering::Release, Ordering::Acquire) {
+            Ok(_) => break,
+            Err(current) => head = current,
+        }
+    }
+}
+
+fn hooks() -> impl Iterator<Item = &'static HotplugHook> {
+    let mut next = NonNull::new(HOOKS.load(Ordering::Acquire));
+    core::iter::from_fn(move || {
+        let hook = unsafe { next?.as_ref() };
+        next = NonNull::new(hook.next.load(Ordering::Acquire));
+        Some(hook)
+    })
+}
+
+/// Returns whether the given CPU is online.
+///
+/// Returns `false` if `cpu_id` is not less than [`percpu_area_num`].
+pub fn is_cpu_online(cpu_id: usize) -> bool {
+    cpu_id < percpu_area_num()
+        && !unsafe { CPU_OFFLINE.remote_ref_raw(cpu_id) }.load(Ordering::Acquire)
+}
+
+/// Marks the given CPU as offline, after calling the `offline` callbacks of
+/// registered hooks.
+///
+/// It should be called before the CPU is actually shut down, while its per-CPU
+/// data is still accessible remotely.
+pub fn cpu_offline(cpu_id: usize) -> Result<(), HotplugError> {
+    if cpu_id >= percpu_area_num() {
+        return Err(HotplugError::InvalidCpuId);
+    }
+    let _lock = HOTPLUG_LOCK.lock();
+    if !is_cpu_online(cpu_id) {
+        return Err(HotplugError::AlreadyOffline);
+    }
+    for hook in hooks() {
+        if let Some(offline) = hook.offline {
+            offline(cpu_id);
+        }
+    }
+    unsafe { CPU_OFFLINE.remote_ref_raw(cpu_id) }.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Marks the given CPU as online, then calls the `online` callbacks of
+/// registered hooks.
+///
+/// If `reset` is `true`, the per-CPU data area of the CPU is restored from the
+/// link-time template first, so that no stale data is left from the last time
+/// it was online. Dynamic per-CPU allocations are kept. The template is
+/// available if the areas do not overlap it (e.g., they are placed by
+/// `init_with_memory` on bare metal), or a copy of it is kept by `try_init`
+/// (on Linux, or if the linker script reserves more areas than CPUs).
+///
+/// It should be called before the CPU starts to access its per-CPU data (and
+/// calls `init_percpu_reg`).
+pub fn cpu_online(cpu_id: usize, reset: bool) -> Result<(), HotplugError> {
+    if cpu_id >= percpu_area_num() {
+        return Err(HotplugError::InvalidCpuId);
+    }
+    let _lock = HOTPLUG_LOCK.lock();
+    if is_cpu_online(cpu_id) {
+        return Err(HotplugError::AlreadyOnline);
+    }
+    if reset {
+        let template = crate::imp::percpu_template().ok_or(HotplugError::TemplateUnavailable)?;
+        unsafe { crate::reset::copy_template(template, cpu_id) };
+    }
+    unsafe { CPU_OFFLINE.remote_ref_raw(cpu_id) }.store(false, Ordering::Release);
+    for hook in hooks() {
+        if let Some(online) = hook.online {
+            online(cpu_id);
+        }
+    }
+    Ok(())
+}
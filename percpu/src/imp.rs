@@ -1,15 +1,29 @@
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use crate::InitError;
 
 static IS_INIT: AtomicBool = AtomicBool::new(false);
 
+/// The base address and number of per-CPU data areas configured at runtime, or
+/// 0 if they follow the linker script.
+static PERCPU_AREA_BASE: AtomicUsize = AtomicUsize::new(0);
+static PERCPU_AREA_NUM: AtomicUsize = AtomicUsize::new(0);
+
+/// The address of the per-CPU data template used to reset areas, or 0 if it
+/// is not available.
+static PERCPU_TEMPLATE: AtomicUsize = AtomicUsize::new(0);
+
+/// The `cpu_id -> base` table of per-CPU data areas allocated separately by
+/// [`init_with_allocator`], or null if the areas are contiguous.
+static PERCPU_AREA_TABLE: AtomicPtr<usize> = AtomicPtr::new(core::ptr::null_mut());
+
 const fn align_up_64(val: usize) -> usize {
     const SIZE_64BIT: usize = 0x40;
     (val + SIZE_64BIT - 1) & !(SIZE_64BIT - 1)
 }
 
-#[cfg(not(target_os = "none"))]
-static PERCPU_AREA_BASE: spin::once::Once<usize> = spin::once::Once::new();
-
 extern "C" {
     fn _percpu_start();
     fn _percpu_end();
@@ -18,8 +32,30 @@ extern "C" {
 }
 
 /// Returns the number of per-CPU data areas reserved.
+///
+/// It is the CPU count passed to [`try_init`] or [`init_with_memory`] after
+/// initialization, or determined by the linker script otherwise (including
+/// after [`init`]).
 pub fn percpu_area_num() -> usize {
-    (_percpu_end as usize - _percpu_start as usize) / align_up_64(percpu_area_size())
+    match PERCPU_AREA_NUM.load(Ordering::Relaxed) {
+        0 => reserved_area_num(),
+        num => num,
+    }
+}
+
+/// Returns whether the per-CPU data areas have been initialized.
+#[cfg(feature = "dynamic")]
+pub(crate) fn is_init() -> bool {
+    IS_INIT.load(Ordering::Acquire)
+}
+
+/// Returns the number of per-CPU data areas reserved by the linker script.
+fn reserved_area_num() -> usize {
+    let size = align_up_64(percpu_area_size());
+    if size == 0 {
+        return 0;
+    }
+    (_percpu_end as *const () as usize - _percpu_start as *const () as usize) / size
 }
 
 /// Returns the per-CPU data area size for one CPU.
@@ -29,21 +65,51 @@ pub fn percpu_area_size() -> usize {
     percpu_symbol_offset!(_percpu_load_end) - percpu_symbol_offset!(_percpu_load_start)
 }
 
+/// Returns whether the `.percpu` section is linked at address 0, so that the
+/// addresses of per-CPU static variables are their offsets in the areas.
+#[cfg(all(feature = "testing", not(feature = "loom")))]
+pub(crate) fn is_section_at_zero() -> bool {
+    use percpu_macros::percpu_symbol_offset;
+    percpu_symbol_offset!(_percpu_load_start) == 0
+}
+
 /// Returns the base address of the per-CPU data area on the given CPU.
 ///
 /// if `cpu_id` is 0, it returns the base address of all per-CPU data areas.
+///
+/// # Panics
+///
+/// Panics if the areas are allocated separately by [`init_with_allocator`],
+/// and `cpu_id` is not less than [`percpu_area_num`].
 pub fn percpu_area_base(cpu_id: usize) -> usize {
-    cfg_if::cfg_if! {
-        if #[cfg(target_os = "none")] {
-            let base = _percpu_start as usize;
-        } else {
-            let base = *PERCPU_AREA_BASE.get().unwrap();
-        }
+    let table = PERCPU_AREA_TABLE.load(Ordering::Acquire);
+    if !table.is_null() {
+        let num = PERCPU_AREA_NUM.load(Ordering::Relaxed);
+        return unsafe { core::slice::from_raw_parts(table, num) }[cpu_id];
     }
+    let base = match PERCPU_AREA_BASE.load(Ordering::Relaxed) {
+        0 => _percpu_start as *const () as usize,
+        base => base,
+    };
     base + cpu_id * align_up_64(percpu_area_size())
 }
 
-/// Initialize all per-CPU data areas.
+/// Returns the address of the per-CPU data template, if it is available.
+///
+/// On bare metal, the template is the `.percpu` section in the load image,
+/// which is the area of CPU 0 unless the areas are placed elsewhere by
+/// [`init_with_memory`] or [`init_with_allocator`]. Otherwise, [`try_init`]
+/// keeps a copy of it in the first area that is reserved by the linker script
+/// but not initialized, if any. On Linux, [`try_init`] allocates an extra
+/// zeroed area as the template, since the areas are zeroed as well.
+pub(crate) fn percpu_template() -> Option<usize> {
+    match PERCPU_TEMPLATE.load(Ordering::Relaxed) {
+        0 => None,
+        template => Some(template),
+    }
+}
+
+/// Initialize all per-CPU data areas reserved by the linker script.
 ///
 /// The number of areas is determined by the following formula:
 ///
@@ -51,48 +117,314 @@ pub fn percpu_area_base(cpu_id: usize) -> usize {
 /// (percpu_section_size / align_up(percpu_area_size, 64)
 /// ```
 ///
+/// `cpu_count` is ignored, so [`percpu_area_num`] stays the number of areas
+/// reserved by the linker script. Use [`try_init`] to initialize only
+/// `cpu_count` areas.
+///
+/// With the `rseq` feature, the areas are initialized for at least every CPU
+/// configured in the system instead (see [`try_init`]).
+///
 /// Returns the number of areas initialized. If this function has been called
 /// before, it does nothing and returns 0.
-pub fn init(_cpu_count: usize) -> usize {
+///
+/// # Panics
+///
+/// Panics if the initialization fails for other reasons. Use [`try_init`] to
+/// handle the errors.
+pub fn init(cpu_count: usize) -> usize {
+    #[cfg(not(feature = "rseq"))]
+    let cpu_count = {
+        let _ = cpu_count;
+        reserved_area_num()
+    };
+    match try_init(cpu_count) {
+        Ok(num) => num,
+        Err(InitError::AlreadyInitialized) => 0,
+        Err(e) => panic!("failed to initialize per-CPU data areas: {}", e),
+    }
+}
+
+/// Initialize per-CPU data areas for `cpu_count` CPUs, in the memory reserved
+/// by the linker script.
+///
+/// The per-CPU data of the primary CPU (area 0) is copied to other CPUs. After
+/// that, [`percpu_area_num`] returns `cpu_count`.
+///
+/// A copy of the per-CPU data template is kept to reset areas with
+/// [`reset_area`](crate::reset_area) or [`cpu_online`](crate::cpu_online),
+/// in the area after the last one if the linker script reserves it on bare
+/// metal, or in an extra area allocated on Linux.
+///
+/// Returns the number of areas initialized, i.e., `cpu_count`.
+///
+/// With the `rseq` feature, the thread may run on any CPU configured in the
+/// system, so the areas are allocated for at least `get_nprocs_conf()` CPUs
+/// regardless of the linker script, and that number is returned.
+///
+/// # Errors
+///
+/// - [`InitError::AlreadyInitialized`] if the areas have been initialized.
+/// - [`InitError::ZeroSizedSection`] if the `.percpu` section is empty.
+/// - [`InitError::InvalidCpuCount`] if `cpu_count` is 0.
+/// - [`InitError::MisalignedSymbols`] if `_percpu_start` is not aligned to 64
+///   bytes.
+/// - [`InitError::SectionTooSmall`] if the linker script reserves less than
+///   `cpu_count` areas.
+/// - [`InitError::AllocationFailed`] if the memory for the areas cannot be
+///   allocated (on Linux).
+pub fn try_init(cpu_count: usize) -> Result<usize, InitError> {
+    if IS_INIT.load(Ordering::SeqCst) {
+        return Err(InitError::AlreadyInitialized);
+    }
+    let size = percpu_area_size();
+    if size == 0 {
+        return Err(InitError::ZeroSizedSection);
+    }
+    if cpu_count == 0 {
+        return Err(InitError::InvalidCpuCount);
+    }
+    #[cfg(target_os = "none")]
+    if _percpu_start as *const () as usize % 64 != 0 {
+        return Err(InitError::MisalignedSymbols);
+    }
+    #[cfg(feature = "rseq")]
+    let cpu_count = cpu_count.max(crate::rseq::configured_cpu_num());
+    #[cfg(not(feature = "rseq"))]
+    {
+        let reserved = reserved_area_num();
+        if cpu_count > reserved {
+            return Err(InitError::SectionTooSmall {
+                requested: cpu_count,
+                reserved,
+            });
+        }
+    }
     // avoid re-initialization.
     if IS_INIT
         .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
         .is_err()
     {
-        return 0;
+        return Err(InitError::AlreadyInitialized);
     }
 
     #[cfg(target_os = "linux")]
     {
-        // we not load the percpu section in ELF, allocate them here.
-        let total_size = _percpu_end as usize - _percpu_start as usize;
-        let layout = std::alloc::Layout::from_size_align(total_size, 0x1000).unwrap();
-        PERCPU_AREA_BASE.call_once(|| unsafe { std::alloc::alloc(layout) as usize });
+        // we not load the percpu section in ELF, allocate them here, with an
+        // extra area as the template.
+        let base = std::alloc::Layout::from_size_align(align_up_64(size) * (cpu_count + 1), 0x1000)
+            .map(|layout| unsafe { std::alloc::alloc_zeroed(layout) as usize })
+            .unwrap_or(0);
+        if base == 0 {
+            IS_INIT.store(false, Ordering::SeqCst);
+            return Err(InitError::AllocationFailed);
+        }
+        PERCPU_AREA_BASE.store(base, Ordering::Relaxed);
+    }
+    PERCPU_AREA_NUM.store(cpu_count, Ordering::Relaxed);
+
+    // keep the template in the area after the last one, which is allocated
+    // above on Linux, or may be reserved by the linker script on bare metal.
+    #[cfg(target_os = "linux")]
+    PERCPU_TEMPLATE.store(percpu_area_base(cpu_count), Ordering::Relaxed);
+    #[cfg(target_os = "none")]
+    if cpu_count < reserved_area_num() {
+        let template = percpu_area_base(cpu_count);
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                percpu_area_base(0) as *const u8,
+                template as *mut u8,
+                size,
+            );
+        }
+        PERCPU_TEMPLATE.store(template, Ordering::Relaxed);
     }
 
     let base = percpu_area_base(0);
-    let size = percpu_area_size();
-    let num = percpu_area_num();
-    for i in 1..num {
+    for i in 1..cpu_count {
         let secondary_base = percpu_area_base(i);
-        #[cfg(target_os = "none")]
-        assert!(secondary_base + size <= _percpu_end as usize);
         // copy per-cpu data of the primary CPU to other CPUs.
         unsafe {
             core::ptr::copy_nonoverlapping(base as *const u8, secondary_base as *mut u8, size);
         }
     }
-    num
+    Ok(cpu_count)
+}
+
+/// Initialize per-CPU data areas for `cpu_count` CPUs in the memory starting
+/// at `base` with `len` bytes, instead of the memory reserved by the linker
+/// script.
+///
+/// It is used when the CPU count is only known at runtime (e.g., discovered
+/// from the firmware). After that, [`percpu_area_base`] and
+/// [`percpu_area_num`] follow the given memory and CPU count. The per-CPU
+/// data of each CPU is copied from the `.percpu` section on bare metal, or
+/// zeroed otherwise.
+///
+/// Returns the number of areas initialized, i.e., `cpu_count`.
+///
+/// # Errors
+///
+/// - [`InitError::AlreadyInitialized`] if the areas have been initialized.
+/// - [`InitError::InvalidCpuCount`] if `cpu_count` is 0.
+/// - [`InitError::MisalignedMemory`] if `base` is not aligned to 64 bytes.
+/// - [`InitError::MemoryTooSmall`] if `len` is less than `cpu_count` areas.
+///
+/// # Safety
+///
+/// The memory must be valid for reads and writes of `len` bytes, and must not
+/// be used for other purposes since then.
+pub unsafe fn init_with_memory(
+    base: NonNull<u8>,
+    len: usize,
+    cpu_count: usize,
+) -> Result<usize, InitError> {
+    if IS_INIT.load(Ordering::SeqCst) {
+        return Err(InitError::AlreadyInitialized);
+    }
+    if cpu_count == 0 {
+        return Err(InitError::InvalidCpuCount);
+    }
+    let base = base.as_ptr() as usize;
+    if base % 64 != 0 {
+        return Err(InitError::MisalignedMemory);
+    }
+    let required = align_up_64(percpu_area_size()).saturating_mul(cpu_count);
+    if len < required {
+        return Err(InitError::MemoryTooSmall {
+            required,
+            provided: len,
+        });
+    }
+    if IS_INIT
+        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        return Err(InitError::AlreadyInitialized);
+    }
+
+    PERCPU_AREA_BASE.store(base, Ordering::Relaxed);
+    PERCPU_AREA_NUM.store(cpu_count, Ordering::Relaxed);
+    #[cfg(target_os = "none")]
+    PERCPU_TEMPLATE.store(_percpu_start as *const () as usize, Ordering::Relaxed);
+
+    for i in 0..cpu_count {
+        // copy the per-CPU data template in the `.percpu` section to each CPU.
+        #[cfg(target_os = "none")]
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                _percpu_start as *const u8,
+                percpu_area_base(i) as *mut u8,
+                percpu_area_size(),
+            );
+        }
+        #[cfg(not(target_os = "none"))]
+        unsafe {
+            core::ptr::write_bytes(percpu_area_base(i) as *mut u8, 0, percpu_area_size());
+        }
+    }
+    Ok(cpu_count)
+}
+
+/// Initialize per-CPU data areas for `cpu_count` CPUs, each in the memory
+/// allocated by `alloc`, instead of the memory reserved by the linker script.
+///
+/// It is used to place the per-CPU data of each CPU in the memory local to its
+/// NUMA node. `alloc` is called with the CPU ID and the layout of an area for
+/// every CPU, and once more with CPU 0 for the `cpu_id -> base` table of
+/// `cpu_count` entries. After that, [`percpu_area_base`] (and so remote
+/// accesses and [`init_percpu_reg`]) looks up the table, while accesses on the
+/// current CPU are still relative to the per-CPU data register. The per-CPU
+/// data of each CPU is copied from the `.percpu` section on bare metal, or
+/// zeroed otherwise.
+///
+/// Returns the number of areas initialized, i.e., `cpu_count`.
+///
+/// # Errors
+///
+/// - [`InitError::AlreadyInitialized`] if the areas have been initialized.
+/// - [`InitError::InvalidCpuCount`] if `cpu_count` is 0.
+/// - [`InitError::AllocationFailed`] if `alloc` returns `None`.
+/// - [`InitError::MisalignedMemory`] if `alloc` returns memory not aligned to
+///   the layout.
+///
+/// The memory already allocated is not freed on errors, and the areas can be
+/// initialized again.
+///
+/// # Safety
+///
+/// The memory returned by `alloc` must be valid for reads and writes of the
+/// requested size, and must not be used for other purposes since then.
+pub unsafe fn init_with_allocator<F>(cpu_count: usize, mut alloc: F) -> Result<usize, InitError>
+where
+    F: FnMut(usize, Layout) -> Option<NonNull<u8>>,
+{
+    if cpu_count == 0 {
+        return Err(InitError::InvalidCpuCount);
+    }
+    if IS_INIT
+        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        return Err(InitError::AlreadyInitialized);
+    }
+
+    let mut alloc = |cpu_id: usize, layout: Layout| match alloc(cpu_id, layout) {
+        Some(ptr) if ptr.as_ptr() as usize % layout.align() == 0 => Ok(ptr.as_ptr()),
+        Some(_) => Err(InitError::MisalignedMemory),
+        None => Err(InitError::AllocationFailed),
+    };
+    let size = percpu_area_size();
+    let table = Layout::array::<usize>(cpu_count)
+        .map_err(|_| InitError::AllocationFailed)
+        .and_then(|layout| alloc(0, layout));
+    let table = match table {
+        Ok(table) => table as *mut usize,
+        Err(e) => {
+            IS_INIT.store(false, Ordering::SeqCst);
+            return Err(e);
+        }
+    };
+    for i in 0..cpu_count {
+        let base = match alloc(i, Layout::from_size_align(align_up_64(size), 64).unwrap()) {
+            Ok(base) => base,
+            Err(e) => {
+                IS_INIT.store(false, Ordering::SeqCst);
+                return Err(e);
+            }
+        };
+        // copy the per-CPU data template in the `.percpu` section to each CPU.
+        #[cfg(target_os = "none")]
+        core::ptr::copy_nonoverlapping(_percpu_start as *const u8, base, size);
+        #[cfg(not(target_os = "none"))]
+        core::ptr::write_bytes(base, 0, size);
+        table.add(i).write(base as usize);
+    }
+
+    PERCPU_AREA_BASE.store(*table, Ordering::Relaxed);
+    PERCPU_AREA_NUM.store(cpu_count, Ordering::Relaxed);
+    PERCPU_AREA_TABLE.store(table, Ordering::Release);
+    #[cfg(target_os = "none")]
+    PERCPU_TEMPLATE.store(_percpu_start as *const () as usize, Ordering::Relaxed);
+    Ok(cpu_count)
 }
 
 /// Reads the architecture-specific per-CPU data register.
 ///
 /// This register is used to hold the per-CPU data base on each CPU.
+///
+/// With the `rseq` feature, it returns the base address of the per-CPU data
+/// area on the CPU that the thread is running on. With the `loom` feature, the
+/// register is a thread-local variable of the current loom thread.
+#[cfg_attr(any(feature = "rseq", feature = "loom"), allow(unused_unsafe))]
 pub fn read_percpu_reg() -> usize {
     let tp;
     unsafe {
         cfg_if::cfg_if! {
-            if #[cfg(target_arch = "x86_64")] {
+            if #[cfg(feature = "rseq")] {
+                tp = crate::rseq::current_area_base();
+            } else if #[cfg(feature = "loom")] {
+                tp = crate::loom::read_percpu_reg();
+            } else if #[cfg(target_arch = "x86_64")] {
                 tp = if cfg!(target_os = "linux") {
                     SELF_PTR.read_current_raw()
                 } else if cfg!(target_os = "none") {
@@ -120,13 +452,22 @@ pub fn read_percpu_reg() -> usize {
 ///
 /// This register is used to hold the per-CPU data base on each CPU.
 ///
+/// No effect with the `rseq` feature. With the `loom` feature, it writes a
+/// thread-local variable of the current loom thread.
+///
 /// # Safety
 ///
 /// This function is unsafe because it writes the low-level register directly.
+#[cfg_attr(any(feature = "rseq", feature = "loom"), allow(unused_unsafe))]
 pub unsafe fn write_percpu_reg(tp: usize) {
     unsafe {
         cfg_if::cfg_if! {
-            if #[cfg(target_arch = "x86_64")] {
+            if #[cfg(feature = "rseq")] {
+                // the area always follows the CPU that the thread is running on.
+                let _ = tp;
+            } else if #[cfg(feature = "loom")] {
+                crate::loom::write_percpu_reg(tp);
+            } else if #[cfg(target_arch = "x86_64")] {
                 if cfg!(target_os = "linux") {
                     const ARCH_SET_GS: u32 = 0x1001;
                     const SYS_ARCH_PRCTL: u32 = 158;
@@ -161,10 +502,18 @@ pub unsafe fn write_percpu_reg(tp: usize) {
 /// the architecture-specific per-CPU data register to the base address of the
 /// corresponding per-CPU data area.
 ///
-/// `cpu_id` indicates which per-CPU data area to use.
+/// `cpu_id` indicates which per-CPU data area to use. It is also recorded in
+/// the area, to be returned by [`this_cpu_id`](crate::this_cpu_id).
+///
+/// No effect with the `rseq` feature, since the per-CPU data area always
+/// follows the CPU that the thread is running on.
 pub fn init_percpu_reg(cpu_id: usize) {
     let tp = percpu_area_base(cpu_id);
-    unsafe { write_percpu_reg(tp) }
+    unsafe {
+        write_percpu_reg(tp);
+        #[cfg(not(feature = "rseq"))]
+        crate::cpu_id::init_current_cpu_id(cpu_id);
+    }
 }
 
 /// To use `percpu::__priv::NoPreemptGuard::new()` and `percpu::percpu_area_base()` in macro expansion.
@@ -175,4 +524,4 @@ use crate as percpu;
 #[cfg(target_arch = "x86_64")]
 #[no_mangle]
 #[percpu_macros::def_percpu]
-static SELF_PTR: usize = 0;
+pub(crate) static SELF_PTR: usize = 0;
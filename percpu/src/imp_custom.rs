@@ -1,11 +1,14 @@
 use core::{
     cell::UnsafeCell,
     fmt::{Debug, Display},
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 #[cfg(feature = "preempt")]
 use kernel_guard::NoPreempt;
 
+use crate::{InitError, IrqSaveGuard};
+
 #[repr(transparent)]
 pub struct PerCpuData<T> {
     data: UnsafeCell<T>,
@@ -36,9 +39,11 @@ impl<T> PerCpuData<T> {
     /// - the CPU ID is valid, and
     /// - data races will not happen.
     #[inline]
-    pub fn remote_ptr(&self, cpu_idx: usize) -> *mut T {
+    pub unsafe fn remote_ptr(&self, cpu_idx: usize) -> *const T {
+        #[cfg(feature = "debug-checks")]
+        assert!(cpu_idx < percpu_area_num(), "invalid CPU ID: {}", cpu_idx);
         let addr = percpu_base() + cpu_idx * percpu_size() + self.offset();
-        addr as *mut T
+        addr as *const T
     }
 
     /// Returns the raw pointer of this per-CPU static variable on the current CPU.
@@ -59,6 +64,8 @@ impl<T> PerCpuData<T> {
     /// Caller must ensure that preemption is disabled on the current CPU.
     #[inline]
     pub unsafe fn current_ref_raw(&self) -> &T {
+        #[cfg(feature = "loom")]
+        crate::loom::track_read(self.current_ptr() as usize);
         &*self.current_ptr()
     }
 
@@ -70,6 +77,8 @@ impl<T> PerCpuData<T> {
     #[inline]
     #[allow(clippy::mut_from_ref)]
     pub unsafe fn current_ref_mut_raw(&self) -> &mut T {
+        #[cfg(feature = "loom")]
+        crate::loom::track_write(self.current_ptr() as usize);
         unsafe { &mut *self.current_ptr() }
     }
 
@@ -88,10 +97,21 @@ impl<T> PerCpuData<T> {
     /// Caller must ensure that preemption is disabled on the current CPU.
     pub unsafe fn write_current_raw(&self, val: T) {
         unsafe {
-            *self.current_ptr() = val;
+            *self.current_ref_mut_raw() = val;
         }
     }
 
+    /// Set the value of the per-CPU static variable on the current CPU, while preemption is disabled as proved by
+    /// `token`.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`get_mut`](Self::get_mut).
+    #[inline]
+    pub unsafe fn write(&self, _token: &mut crate::NoPreemptToken, val: T) {
+        self.write_current_raw(val)
+    }
+
     /// Write the value to the per-CPU variable on the specified CPU.
     ///
     /// # Safety
@@ -101,7 +121,7 @@ impl<T> PerCpuData<T> {
         #[cfg(feature = "preempt")]
         let _g = NoPreempt::new();
         unsafe {
-            *self.remote_ptr(cpu_idx) = val;
+            *self.remote_ref_mut_raw(cpu_idx) = val;
         }
     }
 
@@ -113,7 +133,85 @@ impl<T> PerCpuData<T> {
     {
         #[cfg(feature = "preempt")]
         let _g = NoPreempt::new();
-        unsafe { f(&mut *self.current_ptr()) }
+        unsafe { f(self.current_ref_mut_raw()) }
+    }
+
+    /// Returns the reference of the per-CPU data on the current CPU, while preemption is disabled as proved by
+    /// `token` (from [`scope`](crate::scope) or [`CpuGuard`](crate::CpuGuard)).
+    #[inline]
+    pub fn get<'a>(&'a self, _token: &'a crate::NoPreemptToken) -> &'a T {
+        unsafe { self.current_ref_raw() }
+    }
+
+    /// Returns the mutable reference of the per-CPU data on the current CPU, while preemption is disabled as
+    /// proved by `token`.
+    ///
+    /// The token is borrowed mutably until the reference is dropped, so no other reference can be obtained through
+    /// it meanwhile.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that no reference to the per-CPU data on the current CPU that is not obtained through
+    /// `token` (e.g., through the token of a nested [`scope`](crate::scope) or another
+    /// [`CpuGuard`](crate::CpuGuard), `with_current` or `current()`) is alive while the returned reference is.
+    #[inline]
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn get_mut<'a>(&'a self, _token: &'a mut crate::NoPreemptToken) -> &'a mut T {
+        self.current_ref_mut_raw()
+    }
+
+    /// Returns a guard that dereferences to the per-CPU data on the current CPU.
+    /// Preemption will be disabled until the guard is dropped.
+    #[inline]
+    pub fn current(&self) -> crate::PerCpuRef<'_, T> {
+        crate::PerCpuRef::new(self)
+    }
+
+    /// Returns a guard that mutably dereferences to the per-CPU data on the current CPU.
+    /// Preemption will be disabled until the guard is dropped.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that no other reference to the per-CPU data on the current CPU (e.g., from another
+    /// `current_mut()`, `current()`, `with_current` or `get`) is alive while the guard is held.
+    #[inline]
+    pub unsafe fn current_mut(&self) -> crate::PerCpuRefMut<'_, T> {
+        crate::PerCpuRefMut::new(self)
+    }
+
+    /// Manipulate the per-CPU data on the current CPU in the given closure.
+    /// Local IRQs and preemption will be disabled during the call.
+    pub fn with_current_irqsave<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        let _g = IrqSaveGuard::new();
+        unsafe { f(self.current_ref_mut_raw()) }
+    }
+
+    /// Returns a guard that dereferences to the per-CPU data on the current CPU.
+    /// Local IRQs and preemption will be disabled until the guard is dropped.
+    #[inline]
+    pub fn current_irqsave(&self) -> crate::PerCpuRef<'_, T, IrqSaveGuard> {
+        crate::PerCpuRef::new(self)
+    }
+
+    /// Returns a guard that mutably dereferences to the per-CPU data on the current CPU.
+    /// Local IRQs and preemption will be disabled until the guard is dropped.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`current_mut`](Self::current_mut).
+    #[inline]
+    pub unsafe fn current_mut_irqsave(&self) -> crate::PerCpuRefMut<'_, T, IrqSaveGuard> {
+        crate::PerCpuRefMut::new(self)
+    }
+
+    /// Set the value of the per-CPU static variable on the current CPU. Local IRQs and preemption will be disabled
+    /// during the call.
+    pub fn write_current_irqsave(&self, val: T) {
+        let _g = IrqSaveGuard::new();
+        unsafe { self.write_current_raw(val) };
     }
 
     /// Returns the reference of the per-CPU static variable on the given CPU.
@@ -125,6 +223,8 @@ impl<T> PerCpuData<T> {
     /// - data races will not happen.
     #[inline]
     pub unsafe fn remote_ref_raw(&self, cpu_id: usize) -> &T {
+        #[cfg(feature = "loom")]
+        crate::loom::track_read(self.remote_ptr(cpu_id) as usize);
         &*self.remote_ptr(cpu_id)
     }
 
@@ -138,7 +238,57 @@ impl<T> PerCpuData<T> {
     #[inline]
     #[allow(clippy::mut_from_ref)]
     pub unsafe fn remote_ref_mut_raw(&self, cpu_id: usize) -> &mut T {
-        &mut *self.remote_ptr(cpu_id)
+        #[cfg(feature = "loom")]
+        crate::loom::track_write(self.remote_ptr(cpu_id) as usize);
+        &mut *(self.remote_ptr(cpu_id) as *mut T)
+    }
+
+    /// Returns the reference of the per-CPU static variable on the given CPU, or `None` if `cpu_id` is not less
+    /// than [`percpu_area_num`].
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that data races will not happen.
+    #[inline]
+    pub unsafe fn try_remote_ref(&self, cpu_id: usize) -> Option<&T> {
+        crate::PerCpu::try_remote_ref(self, cpu_id)
+    }
+
+    /// Returns the mutable reference of the per-CPU static variable on the given CPU, or `None` if `cpu_id` is not
+    /// less than [`percpu_area_num`].
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that data races will not happen.
+    #[inline]
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn try_remote_mut(&self, cpu_id: usize) -> Option<&mut T> {
+        crate::PerCpu::try_remote_mut(self, cpu_id)
+    }
+
+    /// Restores the per-CPU static variable on the given CPU to its initial value in the `.percpu` section,
+    /// dropping the old value.
+    ///
+    /// Returns an error if `cpu_idx` is not less than [`percpu_area_num`], or the `.percpu` section is used as the
+    /// per-CPU data area of CPU 0.
+    ///
+    /// # Safety
+    ///
+    /// This function should called with a mutex or before the cpu is online.
+    pub unsafe fn reset_remote(&self, cpu_idx: usize) -> Result<(), crate::ResetError> {
+        crate::PerCpu::reset_remote(self, cpu_idx)
+    }
+
+    /// Returns an iterator that yields `(cpu_id, &data)` of the per-CPU static variable on every CPU whose area is
+    /// initialized.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that the per-CPU data on any CPU is not modified while the iterator or the references
+    /// yielded by it are alive.
+    #[inline]
+    pub unsafe fn iter_remote(&self) -> crate::RemoteIter<'_, Self> {
+        crate::RemoteIter::new(self)
     }
 }
 
@@ -151,20 +301,80 @@ impl<T: Clone> PerCpuData<T> {
         unsafe { self.read_current_raw() }
     }
 
+    /// Returns the value of the per-CPU static variable on the current CPU. Local IRQs and preemption will be
+    /// disabled during the call.
+    pub fn read_current_irqsave(&self) -> T {
+        let _g = IrqSaveGuard::new();
+        unsafe { self.read_current_raw() }
+    }
+
     /// Returns the value of the per-CPU static variable on the current CPU.
     ///
     /// # Safety
     ///
     /// Caller must ensure that preemption is disabled on the current CPU.
     pub unsafe fn read_current_raw(&self) -> T {
-        unsafe { (*self.current_ptr()).clone() }
+        unsafe { self.current_ref_raw().clone() }
+    }
+
+    /// Returns the value of the per-CPU static variable on the current CPU, while preemption is disabled as proved
+    /// by `token`.
+    #[inline]
+    pub fn read(&self, _token: &crate::NoPreemptToken) -> T {
+        unsafe { self.read_current_raw() }
     }
 
     /// Returns the value of the per-CPU static variable on the given CPU.
     pub fn read_remote(&self, cpu_idx: usize) -> T {
         #[cfg(feature = "preempt")]
         let _g = NoPreempt::new();
-        unsafe { (*self.remote_ptr(cpu_idx)).clone() }
+        unsafe { self.remote_ref_raw(cpu_idx).clone() }
+    }
+
+    /// Returns the value of the per-CPU static variable on the given CPU, or `None` if `cpu_idx` is not less than
+    /// [`percpu_area_num`].
+    pub fn try_read_remote(&self, cpu_idx: usize) -> Option<T> {
+        (cpu_idx < percpu_area_num()).then(|| self.read_remote(cpu_idx))
+    }
+
+    /// Folds the values of the per-CPU static variable on all CPUs into an accumulator, reading each of them with
+    /// [`read_remote`](Self::read_remote).
+    ///
+    /// The values are read one by one, so the result is not an atomic snapshot if they are updated concurrently.
+    pub fn fold_remote<B, F>(&self, init: B, f: F) -> B
+    where
+        F: FnMut(B, T) -> B,
+    {
+        crate::PerCpu::fold_remote(self, init, f)
+    }
+}
+
+impl<T: Clone + core::iter::Sum> PerCpuData<T> {
+    /// Returns the sum of the per-CPU static variable on all CPUs.
+    ///
+    /// Like [`fold_remote`](Self::fold_remote), it is not an atomic snapshot if the values are updated concurrently.
+    pub fn sum_remote(&self) -> T {
+        crate::PerCpu::sum_remote(self)
+    }
+}
+
+impl<T> crate::PerCpu for PerCpuData<T> {
+    type Target = T;
+    type Guard = crate::NoPreemptGuard;
+
+    #[inline]
+    fn offset(&self) -> usize {
+        PerCpuData::offset(self)
+    }
+
+    #[inline]
+    unsafe fn current_ptr(&self) -> *const T {
+        PerCpuData::current_ptr(self)
+    }
+
+    #[inline]
+    unsafe fn remote_ptr(&self, cpu_id: usize) -> *const T {
+        PerCpuData::remote_ptr(self, cpu_id)
     }
 }
 
@@ -195,7 +405,7 @@ unsafe extern "C" {
 }
 
 pub fn percpu_section_size() -> usize {
-    _percpu_load_end as usize - _percpu_load_start as usize
+    _percpu_load_end as *const () as usize - _percpu_load_start as *const () as usize
 }
 
 #[inline]
@@ -204,7 +414,7 @@ fn percpu_base() -> usize {
 }
 #[inline]
 fn percpu_link_start() -> usize {
-    _percpu_load_start as usize
+    _percpu_load_start as *const () as usize
 }
 
 #[macro_export]
@@ -228,39 +438,127 @@ macro_rules! impl_percpu {
     };
 }
 
+static IS_INIT: AtomicBool = AtomicBool::new(false);
 static mut PERCPU_SIZE: usize = 0;
+static mut PERCPU_NUM: usize = 0;
+
+/// Returns the number of per-CPU data areas initialized by [`init`].
+pub fn percpu_area_num() -> usize {
+    unsafe { PERCPU_NUM }
+}
+
+/// Returns whether the per-CPU data areas have been initialized.
+#[cfg(feature = "dynamic")]
+pub(crate) fn is_init() -> bool {
+    IS_INIT.load(Ordering::Acquire)
+}
 
 #[inline]
 fn percpu_size() -> usize {
     unsafe { PERCPU_SIZE }
 }
 
+/// Returns the per-CPU data area size for one CPU.
+pub fn percpu_area_size() -> usize {
+    percpu_section_size()
+}
+
+/// Returns the base address of the per-CPU data area on the given CPU.
+pub fn percpu_area_base(cpu_id: usize) -> usize {
+    percpu_base() + cpu_id * percpu_size()
+}
+
+/// Returns the address of the per-CPU data template (the `.percpu` section in the image), if it is not used as the
+/// per-CPU data area of CPU 0.
+pub(crate) fn percpu_template() -> Option<usize> {
+    let template = percpu_link_start();
+    if percpu_base() == template {
+        None
+    } else {
+        Some(template)
+    }
+}
+
 #[inline]
 fn get_cpu_local_ptr() -> *mut u8 {
     unsafe { _percpu_get_cpu_local_ptr() }
 }
 
+/// Initialize per-CPU data areas for `cpu_count` CPUs, by copying the `.percpu` section to each area.
+///
+/// If this function has been called before, the `.percpu` section is copied to the first `cpu_count` areas again, and
+/// [`percpu_area_num`] returns `cpu_count` since then, like after the first call.
+///
+/// # Panics
+///
+/// Panics if the initialization fails for other reasons. Use [`try_init`] to handle the errors.
 pub fn init(cpu_count: usize) {
+    match try_init(cpu_count) {
+        Ok(_) => {}
+        Err(InitError::AlreadyInitialized) => unsafe {
+            PERCPU_NUM = cpu_count;
+            copy_template(cpu_count);
+        },
+        Err(e) => panic!("failed to initialize per-CPU data areas: {}", e),
+    }
+}
+
+/// Initialize per-CPU data areas for `cpu_count` CPUs, by copying the `.percpu` section to each area.
+///
+/// Unlike [`init`], it fails instead of copying the section again if the areas have been initialized.
+///
+/// Returns the number of areas initialized, i.e., `cpu_count`.
+///
+/// # Errors
+///
+/// - [`InitError::AlreadyInitialized`] if the areas have been initialized.
+/// - [`InitError::ZeroSizedSection`] if the `.percpu` section is empty.
+/// - [`InitError::InvalidCpuCount`] if `cpu_count` is 0.
+pub fn try_init(cpu_count: usize) -> Result<usize, InitError> {
+    if IS_INIT.load(Ordering::SeqCst) {
+        return Err(InitError::AlreadyInitialized);
+    }
+    if percpu_section_size() == 0 {
+        return Err(InitError::ZeroSizedSection);
+    }
+    if cpu_count == 0 {
+        return Err(InitError::InvalidCpuCount);
+    }
+    if IS_INIT
+        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        return Err(InitError::AlreadyInitialized);
+    }
+
     unsafe {
         PERCPU_SIZE = percpu_section_size();
+        PERCPU_NUM = cpu_count;
+        copy_template(cpu_count);
+    }
+    Ok(cpu_count)
+}
 
-        let src = core::slice::from_raw_parts(percpu_link_start() as *const u8, percpu_size());
+/// Copies the `.percpu` section to the areas of the first `cpu_count` CPUs.
+unsafe fn copy_template(cpu_count: usize) {
+    let src = core::slice::from_raw_parts(percpu_link_start() as *const u8, percpu_size());
 
-        for i in 0..cpu_count {
-            let ptr = (percpu_base() + i * PERCPU_SIZE) as *mut u8;
+    for i in 0..cpu_count {
+        let ptr = (percpu_base() + i * percpu_size()) as *mut u8;
 
-            let dst = core::slice::from_raw_parts_mut(ptr, percpu_size());
+        let dst = core::slice::from_raw_parts_mut(ptr, percpu_size());
 
-            if i == 0 && dst.eq(&src) {
-                continue;
-            }
-            dst.copy_from_slice(src);
+        if i == 0 && dst.eq(&src) {
+            continue;
         }
+        dst.copy_from_slice(src);
     }
 }
+
 pub fn init_percpu_reg(cpu_idx: usize) {
     unsafe {
         let ptr = (percpu_base() + cpu_idx * percpu_size()) as *mut u8;
         _percpu_set_cpu_local_ptr(ptr);
+        crate::cpu_id::init_current_cpu_id(cpu_idx);
     }
 }
@@ -0,0 +1,57 @@
+//! Iteration of a per-CPU variable across all CPUs.
+
+use core::{iter::FusedIterator, ops::Range};
+
+use crate::{percpu_area_num, PerCpu};
+
+/// An iterator over the per-CPU data of a variable on all CPUs, returned by
+/// `iter_remote()`.
+///
+/// It yields `(cpu_id, &data)` for every per-CPU data area initialized, in the
+/// order of CPU IDs.
+pub struct RemoteIter<'a, P: PerCpu + ?Sized> {
+    var: &'a P,
+    cpus: Range<usize>,
+}
+
+impl<'a, P: PerCpu + ?Sized> RemoteIter<'a, P> {
+    /// Creates an iterator over the per-CPU data of `var` on all CPUs.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that the per-CPU data on any CPU is not modified
+    /// while the iterator or the references yielded by it are alive.
+    pub unsafe fn new(var: &'a P) -> Self {
+        Self {
+            var,
+            cpus: 0..percpu_area_num(),
+        }
+    }
+}
+
+impl<'a, P: PerCpu + ?Sized> Iterator for RemoteIter<'a, P> {
+    type Item = (usize, &'a P::Target);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let cpu_id = self.cpus.next()?;
+        Some((cpu_id, unsafe { self.var.remote_ref_raw(cpu_id) }))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.cpus.size_hint()
+    }
+}
+
+impl<P: PerCpu + ?Sized> DoubleEndedIterator for RemoteIter<'_, P> {
+    #[inline]
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let cpu_id = self.cpus.next_back()?;
+        Some((cpu_id, unsafe { self.var.remote_ref_raw(cpu_id) }))
+    }
+}
+
+impl<P: PerCpu + ?Sized> ExactSizeIterator for RemoteIter<'_, P> {}
+
+impl<P: PerCpu + ?Sized> FusedIterator for RemoteIter<'_, P> {}
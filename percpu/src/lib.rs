@@ -4,6 +4,9 @@
 
 extern crate percpu_macros;
 
+/// To use `percpu::...` paths in macro expansion inside this crate.
+extern crate self as percpu;
+
 cfg_if::cfg_if! {
     if #[cfg(feature = "sp-naive")] {
         #[path = "naive.rs"]
@@ -16,15 +19,85 @@ cfg_if::cfg_if! {
     }
 }
 
+#[cfg(feature = "dynamic")]
+mod counter;
+mod cpu_id;
+#[cfg(feature = "dynamic")]
+mod dynamic;
+mod guard;
+mod hotplug;
+mod iter;
+#[cfg(feature = "loom")]
+#[cfg_attr(docsrs, doc(cfg(feature = "loom")))]
+pub mod loom;
+mod primitive;
+mod reset;
+#[cfg(feature = "rseq")]
+mod rseq;
+mod snapshot;
+#[cfg(feature = "testing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "testing")))]
+pub mod testing;
+
+#[cfg(all(
+    feature = "rseq",
+    any(
+        feature = "sp-naive",
+        feature = "custom-tp",
+        not(all(target_os = "linux", target_arch = "x86_64"))
+    )
+))]
+compile_error!("the `rseq` feature is only supported by the default backend on x86_64 Linux");
+
+#[cfg(all(
+    feature = "loom",
+    any(feature = "sp-naive", feature = "rseq", target_os = "none")
+))]
+compile_error!("the `loom` feature requires `std` and the default or `custom-tp` backend");
+
+#[cfg(all(feature = "testing", any(feature = "rseq", target_os = "none")))]
+compile_error!("the `testing` feature requires `std` and per-thread per-CPU data registers");
+
 use core::ptr::NonNull;
 
+#[cfg(feature = "dynamic")]
+#[cfg_attr(docsrs, doc(cfg(feature = "dynamic")))]
+pub use self::counter::PerCpuCounter;
+#[cfg(all(feature = "dynamic", target_has_atomic = "64"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "dynamic")))]
+pub use self::counter::PerCpuCounter64;
+pub use self::cpu_id::{cpu_id_of_area, this_cpu_id};
+#[cfg(feature = "dynamic")]
+#[cfg_attr(docsrs, doc(cfg(feature = "dynamic")))]
+pub use self::dynamic::{alloc_percpu, PerCpuBox, DYNAMIC_AREA_SIZE};
+pub use self::guard::{
+    scope, scope_irqsave, CpuGuard, IrqSaveGuard, NoPreemptGuard, NoPreemptToken, PerCpuGuard,
+    PerCpuRef, PerCpuRefMut,
+};
+pub use self::hotplug::{
+    cpu_offline, cpu_online, is_cpu_online, register_hotplug_hook, HotplugError, HotplugHook,
+};
 pub use self::imp::*;
+pub use self::iter::RemoteIter;
+pub use self::primitive::{PerCpuInteger, PerCpuPrimitive};
+pub use self::reset::{reset_area, ResetError};
+pub use self::snapshot::{
+    percpu_layout_hash, restore_area, snapshot_area, snapshot_size, SnapshotError,
+    SNAPSHOT_HEADER_SIZE,
+};
 pub use percpu_macros::def_percpu;
 
 #[doc(hidden)]
 pub mod __priv {
-    #[cfg(feature = "preempt")]
-    pub use kernel_guard::NoPreempt as NoPreemptGuard;
+    pub use crate::guard::{IrqSaveGuard, NoPreemptGuard};
+    #[cfg(feature = "loom")]
+    pub use crate::loom::{track_read, track_write};
+    pub use crate::primitive::{Dispatch, DispatchOther, DispatchPrimitive};
+    #[cfg(feature = "rseq")]
+    pub use crate::rseq::{
+        cmpxchg_current, current_area_base, fetch_add_current, read_current, write_current, RseqInt,
+    };
+    pub use crate::snapshot::LayoutEntry;
 }
 
 cfg_if::cfg_if! {
@@ -36,8 +109,480 @@ cfg_if::cfg_if! {
     }
 }
 
+/// Common interface of per-CPU static variables defined with [`def_percpu`].
+///
+/// It is implemented by the wrapper structs generated by the default and
+/// `sp-naive` backends, as well as by `PerCpuData` used by the `custom-tp`
+/// backend, so that libraries can be generic over per-CPU variables and
+/// switch backends with a feature flag only.
+///
+/// Inherent methods of the same name take precedence in method calls, while
+/// generic code calls the methods of this trait.
+///
+/// # `rseq`
+///
+/// With the `rseq` feature, preemption cannot be disabled in userspace, so
+/// [`Guard`](Self::Guard) does not keep the thread on the CPU. The accessors
+/// that reference the per-CPU data on the current CPU (e.g., `with_current`,
+/// `current`, `get`, `get_mut`) may keep using the data of the last CPU after
+/// the thread is migrated, while other threads on that CPU access it as well.
+/// They are only correct if no two threads access the data of a CPU at the
+/// same time, e.g., every thread is pinned to its own CPU. The accessors
+/// generated by [`def_percpu`] for primitive types (`read_current`,
+/// `write_current`, `add_current`, `cmpxchg_current`, ...) are rseq critical
+/// sections or atomic, so they are always correct.
+pub trait PerCpu {
+    /// The type of the per-CPU data.
+    type Target;
+
+    /// The guard of the default accessors (e.g., `with_current`, `current`),
+    /// which is [`IrqSaveGuard`] for per-CPU variables defined with
+    /// `#[def_percpu(irq_safe)]`, and [`NoPreemptGuard`] otherwise.
+    type Guard: PerCpuGuard;
+
+    /// Returns the offset relative to the per-CPU data area base.
+    fn offset(&self) -> usize;
+
+    /// Returns the raw pointer of this per-CPU static variable on the current CPU.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that preemption is disabled on the current CPU.
+    unsafe fn current_ptr(&self) -> *const Self::Target;
+
+    /// Returns the raw pointer of this per-CPU static variable on the given CPU.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that
+    /// - the CPU ID is valid, and
+    /// - data races will not happen.
+    unsafe fn remote_ptr(&self, cpu_id: usize) -> *const Self::Target;
+
+    /// Returns the value of the per-CPU static variable on the current CPU.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that preemption is disabled on the current CPU.
+    unsafe fn read_current_raw(&self) -> Self::Target
+    where
+        Self::Target: Clone,
+    {
+        self.current_ref_raw().clone()
+    }
+
+    /// Set the value of the per-CPU static variable on the current CPU.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that preemption is disabled on the current CPU.
+    unsafe fn write_current_raw(&self, val: Self::Target) {
+        *self.current_ref_mut_raw() = val
+    }
+
+    /// Returns the reference of the per-CPU static variable on the current CPU.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that preemption is disabled on the current CPU.
+    #[inline]
+    unsafe fn current_ref_raw(&self) -> &Self::Target {
+        #[cfg(feature = "loom")]
+        crate::loom::track_read(self.current_ptr() as usize);
+        &*self.current_ptr()
+    }
+
+    /// Returns the mutable reference of the per-CPU static variable on the current CPU.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that preemption is disabled on the current CPU.
+    #[inline]
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn current_ref_mut_raw(&self) -> &mut Self::Target {
+        #[cfg(feature = "loom")]
+        crate::loom::track_write(self.current_ptr() as usize);
+        &mut *(self.current_ptr() as *mut Self::Target)
+    }
+
+    /// Returns the reference of the per-CPU static variable on the given CPU.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that
+    /// - the CPU ID is valid, and
+    /// - data races will not happen.
+    #[inline]
+    unsafe fn remote_ref_raw(&self, cpu_id: usize) -> &Self::Target {
+        #[cfg(feature = "loom")]
+        crate::loom::track_read(self.remote_ptr(cpu_id) as usize);
+        &*self.remote_ptr(cpu_id)
+    }
+
+    /// Returns the mutable reference of the per-CPU static variable on the given CPU.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that
+    /// - the CPU ID is valid, and
+    /// - data races will not happen.
+    #[inline]
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn remote_ref_mut_raw(&self, cpu_id: usize) -> &mut Self::Target {
+        #[cfg(feature = "loom")]
+        crate::loom::track_write(self.remote_ptr(cpu_id) as usize);
+        &mut *(self.remote_ptr(cpu_id) as *mut Self::Target)
+    }
+
+    /// Returns the reference of the per-CPU static variable on the given CPU,
+    /// or `None` if `cpu_id` is not less than [`percpu_area_num`].
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that data races will not happen.
+    #[inline]
+    unsafe fn try_remote_ref(&self, cpu_id: usize) -> Option<&Self::Target> {
+        (cpu_id < percpu_area_num()).then(|| self.remote_ref_raw(cpu_id))
+    }
+
+    /// Returns the mutable reference of the per-CPU static variable on the
+    /// given CPU, or `None` if `cpu_id` is not less than [`percpu_area_num`].
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that data races will not happen.
+    #[inline]
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn try_remote_mut(&self, cpu_id: usize) -> Option<&mut Self::Target> {
+        (cpu_id < percpu_area_num()).then(|| self.remote_ref_mut_raw(cpu_id))
+    }
+
+    /// Manipulate the per-CPU data on the current CPU in the given closure.
+    /// It runs in the critical section of [`Guard`](Self::Guard) (e.g.,
+    /// preemption disabled).
+    fn with_current<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Self::Target) -> R,
+    {
+        let _guard = CpuGuard::<Self::Guard>::enter();
+        f(unsafe { self.current_ref_mut_raw() })
+    }
+
+    /// Returns a guard that dereferences to the per-CPU data on the current
+    /// CPU. The critical section of [`Guard`](Self::Guard) (e.g., preemption
+    /// disabled) lasts until the guard is dropped.
+    fn current(&self) -> PerCpuRef<'_, Self::Target, Self::Guard> {
+        PerCpuRef::new(self)
+    }
+
+    /// Returns a guard that mutably dereferences to the per-CPU data on the
+    /// current CPU. The critical section of [`Guard`](Self::Guard) (e.g.,
+    /// preemption disabled) lasts until the guard is dropped.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that no other reference to the per-CPU data on the
+    /// current CPU (e.g., from another `current_mut()`, `current()`,
+    /// `with_current` or `get`) is alive while the guard is held.
+    unsafe fn current_mut(&self) -> PerCpuRefMut<'_, Self::Target, Self::Guard> {
+        PerCpuRefMut::new(self)
+    }
+
+    /// Returns the reference of the per-CPU data on the current CPU, while
+    /// preemption is disabled as proved by `token` (from a [`scope`] or a
+    /// [`CpuGuard`]).
+    #[inline]
+    fn get<'a>(&'a self, _token: &'a NoPreemptToken) -> &'a Self::Target {
+        unsafe { self.current_ref_raw() }
+    }
+
+    /// Returns the mutable reference of the per-CPU data on the current CPU,
+    /// while preemption is disabled as proved by `token`.
+    ///
+    /// The token is borrowed mutably until the reference is dropped, so no
+    /// other reference can be obtained through it meanwhile.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that no reference to the per-CPU data on the current
+    /// CPU that is not obtained through `token` (e.g., through the token of a
+    /// nested [`scope`] or another [`CpuGuard`], `with_current` or `current()`)
+    /// is alive while the returned reference is.
+    #[inline]
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn get_mut<'a>(&'a self, _token: &'a mut NoPreemptToken) -> &'a mut Self::Target {
+        self.current_ref_mut_raw()
+    }
+
+    /// Returns the value of the per-CPU data on the current CPU, while
+    /// preemption is disabled as proved by `token`.
+    #[inline]
+    fn read(&self, _token: &NoPreemptToken) -> Self::Target
+    where
+        Self::Target: Clone,
+    {
+        unsafe { self.read_current_raw() }
+    }
+
+    /// Set the value of the per-CPU data on the current CPU, while preemption
+    /// is disabled as proved by `token`.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`get_mut`](Self::get_mut).
+    #[inline]
+    unsafe fn write(&self, _token: &mut NoPreemptToken, val: Self::Target) {
+        self.write_current_raw(val)
+    }
+
+    /// Manipulate the per-CPU data on the current CPU in the given closure.
+    /// Local IRQs and preemption will be disabled during the call.
+    fn with_current_irqsave<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Self::Target) -> R,
+    {
+        let _guard = IrqSaveGuard::new();
+        f(unsafe { self.current_ref_mut_raw() })
+    }
+
+    /// Returns a guard that dereferences to the per-CPU data on the current
+    /// CPU. Local IRQs and preemption will be disabled until the guard is
+    /// dropped.
+    fn current_irqsave(&self) -> PerCpuRef<'_, Self::Target, IrqSaveGuard> {
+        PerCpuRef::new(self)
+    }
+
+    /// Returns a guard that mutably dereferences to the per-CPU data on the
+    /// current CPU. Local IRQs and preemption will be disabled until the guard
+    /// is dropped.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`current_mut`](Self::current_mut).
+    unsafe fn current_mut_irqsave(&self) -> PerCpuRefMut<'_, Self::Target, IrqSaveGuard> {
+        PerCpuRefMut::new(self)
+    }
+
+    /// Returns the value of the per-CPU static variable on the current CPU.
+    /// It runs in the critical section of [`Guard`](Self::Guard) (e.g.,
+    /// preemption disabled).
+    fn read_current(&self) -> Self::Target
+    where
+        Self::Target: Clone,
+    {
+        let _guard = CpuGuard::<Self::Guard>::enter();
+        unsafe { self.read_current_raw() }
+    }
+
+    /// Set the value of the per-CPU static variable on the current CPU.
+    /// It runs in the critical section of [`Guard`](Self::Guard) (e.g.,
+    /// preemption disabled).
+    fn write_current(&self, val: Self::Target) {
+        let _guard = CpuGuard::<Self::Guard>::enter();
+        unsafe { self.write_current_raw(val) }
+    }
+
+    /// Returns the value of the per-CPU static variable on the current CPU.
+    /// Local IRQs and preemption will be disabled during the call.
+    fn read_current_irqsave(&self) -> Self::Target
+    where
+        Self::Target: Clone,
+    {
+        let _guard = IrqSaveGuard::new();
+        unsafe { self.read_current_raw() }
+    }
+
+    /// Set the value of the per-CPU static variable on the current CPU.
+    /// Local IRQs and preemption will be disabled during the call.
+    fn write_current_irqsave(&self, val: Self::Target) {
+        let _guard = IrqSaveGuard::new();
+        unsafe { self.write_current_raw(val) }
+    }
+
+    /// Returns the value of the per-CPU static variable on the given CPU.
+    /// It runs in the critical section of [`Guard`](Self::Guard) (e.g.,
+    /// preemption disabled).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cpu_id` is not less than [`percpu_area_num`].
+    fn read_remote(&self, cpu_id: usize) -> Self::Target
+    where
+        Self::Target: Clone,
+    {
+        assert!(cpu_id < percpu_area_num(), "invalid CPU ID: {}", cpu_id);
+        let _guard = CpuGuard::<Self::Guard>::enter();
+        unsafe { self.remote_ref_raw(cpu_id).clone() }
+    }
+
+    /// Returns the value of the per-CPU static variable on the given CPU, or
+    /// `None` if `cpu_id` is not less than [`percpu_area_num`]. It runs in the
+    /// critical section of [`Guard`](Self::Guard).
+    fn try_read_remote(&self, cpu_id: usize) -> Option<Self::Target>
+    where
+        Self::Target: Clone,
+    {
+        (cpu_id < percpu_area_num()).then(|| self.read_remote(cpu_id))
+    }
+
+    /// Set the value of the per-CPU static variable on the given CPU.
+    /// It runs in the critical section of [`Guard`](Self::Guard) (e.g.,
+    /// preemption disabled).
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that data races will not happen, i.e., the per-CPU
+    /// data on that CPU is not accessed concurrently (for example, the CPU is
+    /// not online yet, or all accesses are serialized by a lock).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cpu_id` is not less than [`percpu_area_num`].
+    unsafe fn write_remote(&self, cpu_id: usize, val: Self::Target) {
+        assert!(cpu_id < percpu_area_num(), "invalid CPU ID: {}", cpu_id);
+        let _guard = CpuGuard::<Self::Guard>::enter();
+        unsafe { *self.remote_ref_mut_raw(cpu_id) = val }
+    }
+
+    /// Restores the per-CPU data on the given CPU to its initial value in the
+    /// link-time template, dropping the old value. It runs in the critical
+    /// section of [`Guard`](Self::Guard).
+    ///
+    /// # Errors
+    ///
+    /// - [`ResetError::InvalidCpuId`] if `cpu_id` is not less than
+    ///   [`percpu_area_num`].
+    /// - [`ResetError::TemplateUnavailable`] if the template is overlapped by
+    ///   the per-CPU data areas and no copy of it is kept (see
+    ///   [`reset_area`]).
+    /// - [`ResetError::DynamicAllocation`] if the per-CPU data is allocated by
+    ///   `alloc_percpu`.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`write_remote`](Self::write_remote).
+    unsafe fn reset_remote(&self, cpu_id: usize) -> Result<(), ResetError> {
+        if reset::is_dynamic(self.offset()) {
+            return Err(ResetError::DynamicAllocation);
+        }
+        let template = reset::template_for(cpu_id)?;
+        // a bitwise copy of the initializer of the static variable, like an
+        // instance of a constant.
+        let init = core::ptr::read((template + self.offset()) as *const Self::Target);
+        self.write_remote(cpu_id, init);
+        Ok(())
+    }
+
+    /// Returns an iterator that yields `(cpu_id, &data)` of the per-CPU data
+    /// on every CPU whose area is initialized.
+    ///
+    /// # Safety
+    ///
+    /// Caller must ensure that the per-CPU data on any CPU is not modified
+    /// while the iterator or the references yielded by it are alive.
+    #[inline]
+    unsafe fn iter_remote(&self) -> RemoteIter<'_, Self> {
+        RemoteIter::new(self)
+    }
+
+    /// Folds the values of the per-CPU data on all CPUs into an accumulator,
+    /// reading each of them with [`read_remote`](Self::read_remote).
+    ///
+    /// The values are read one by one, so the result is not an atomic snapshot
+    /// if they are updated concurrently.
+    fn fold_remote<B, F>(&self, init: B, mut f: F) -> B
+    where
+        Self::Target: Clone,
+        F: FnMut(B, Self::Target) -> B,
+    {
+        (0..percpu_area_num()).fold(init, |acc, cpu_id| f(acc, self.read_remote(cpu_id)))
+    }
+
+    /// Returns the sum of the per-CPU data on all CPUs.
+    ///
+    /// Like [`fold_remote`](Self::fold_remote), it is not an atomic snapshot
+    /// if the values are updated concurrently.
+    fn sum_remote(&self) -> Self::Target
+    where
+        Self::Target: Clone + core::iter::Sum,
+    {
+        (0..percpu_area_num())
+            .map(|cpu_id| self.read_remote(cpu_id))
+            .sum()
+    }
+}
+
+/// Errors returned by the initialization of per-CPU data areas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitError {
+    /// The per-CPU data areas have already been initialized.
+    AlreadyInitialized,
+    /// The CPU count is zero.
+    InvalidCpuCount,
+    /// The memory for per-CPU data areas is not aligned to 64 bytes.
+    MisalignedMemory,
+    /// The memory is too small to hold the per-CPU data areas of all CPUs.
+    MemoryTooSmall {
+        /// The required size in bytes.
+        required: usize,
+        /// The provided size in bytes.
+        provided: usize,
+    },
+    /// The `.percpu` section reserved by the linker script is too small for
+    /// the requested number of CPUs.
+    SectionTooSmall {
+        /// The requested number of CPUs.
+        requested: usize,
+        /// The number of per-CPU data areas reserved.
+        reserved: usize,
+    },
+    /// The linker symbols of the `.percpu` section are not aligned to 64
+    /// bytes.
+    MisalignedSymbols,
+    /// Failed to allocate memory for per-CPU data areas.
+    AllocationFailed,
+    /// The `.percpu` section is empty.
+    ZeroSizedSection,
+}
+
+impl core::fmt::Display for InitError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::AlreadyInitialized => write!(f, "per-CPU data areas are already initialized"),
+            Self::InvalidCpuCount => write!(f, "invalid CPU count"),
+            Self::MisalignedMemory => {
+                write!(
+                    f,
+                    "memory for per-CPU data areas is not aligned to 64 bytes"
+                )
+            }
+            Self::MemoryTooSmall { required, provided } => write!(
+                f,
+                "memory for per-CPU data areas is too small: {:#x} < {:#x}",
+                provided, required
+            ),
+            Self::SectionTooSmall {
+                requested,
+                reserved,
+            } => write!(
+                f,
+                "`.percpu` section is too small: {} areas reserved for {} CPUs",
+                reserved, requested
+            ),
+            Self::MisalignedSymbols => write!(f, "`.percpu` section is not aligned to 64 bytes"),
+            Self::AllocationFailed => write!(f, "failed to allocate per-CPU data areas"),
+            Self::ZeroSizedSection => write!(f, "`.percpu` section is empty"),
+        }
+    }
+}
+
+/// Low-level interfaces for the `custom-tp` backend, which must be implemented
+/// by the crate user and registered with `impl_percpu!`.
 pub trait Impl {
+    /// Returns the base address of all per-CPU data areas.
     fn percpu_base() -> NonNull<u8>;
+    /// Sets the pointer to the per-CPU data area of the current CPU.
     fn set_cpu_local_ptr(ptr: *mut u8);
+    /// Returns the pointer to the per-CPU data area of the current CPU.
     fn get_cpu_local_ptr() -> *mut u8;
 }
@@ -0,0 +1,131 @@
+//! Model checking per-CPU data accesses with [loom](https://docs.rs/loom).
+//!
+//! With the `loom` feature, every access to the per-CPU data through a
+//! reference or a value (e.g., `with_current`, `read_current`, `write_remote`,
+//! `remote_ref_mut_raw`, `add_current`) is recorded on a loom-tracked cell of
+//! its address, so that loom reports accesses of the same per-CPU data from
+//! different threads that are not ordered by synchronization, such as a remote
+//! write racing with a local `with_current`.
+//!
+//! Simulated CPUs are loom threads spawned by [`spawn_on`] in the closure of
+//! [`model`], each of which is bound to the per-CPU data area of its CPU. With
+//! the `custom-tp` backend, the `Impl` must keep the current per-CPU data area
+//! in a `loom::thread_local!`, since loom threads share an OS thread.
+//!
+//! Accesses to the per-CPU data outside [`model`] panic.
+//!
+//! ```no_run
+//! # use percpu::{def_percpu, PerCpu};
+//! #[def_percpu]
+//! static VALUE: usize = 0;
+//!
+//! percpu::init(2);
+//! percpu::loom::model(|| {
+//!     let cpu1 = percpu::loom::spawn_on(1, || unsafe { PerCpu::write_remote(&VALUE, 0, 1) });
+//!     VALUE.with_current(|v| *v += 1); // on CPU 0, reported as a data race
+//!     cpu1.join().unwrap();
+//! });
+//! ```
+
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use ::loom::cell::UnsafeCell;
+
+use crate::{init_percpu_reg, percpu_area_base, percpu_area_num, percpu_area_size};
+
+/// The loom-tracked cells of accessed per-CPU data, by address. They are
+/// created in the current execution of the model, and cleared before the next.
+static CELLS: Mutex<BTreeMap<usize, Box<UnsafeCell<()>>>> = Mutex::new(BTreeMap::new());
+
+/// Serializes models, since they share the per-CPU data areas.
+static MODEL: Mutex<()> = Mutex::new(());
+
+#[cfg(not(feature = "custom-tp"))]
+::loom::thread_local! {
+    /// The per-CPU data register of the current loom thread.
+    static PERCPU_REG: core::cell::Cell<usize> = core::cell::Cell::new(0);
+}
+
+#[cfg(not(feature = "custom-tp"))]
+pub(crate) fn read_percpu_reg() -> usize {
+    PERCPU_REG.with(|reg| reg.get())
+}
+
+#[cfg(not(feature = "custom-tp"))]
+pub(crate) fn write_percpu_reg(tp: usize) {
+    PERCPU_REG.with(|reg| reg.set(tp))
+}
+
+/// Locks the mutex even if a previous model panicked with it held, e.g., when
+/// loom reports a data race.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn with_cell<R>(addr: usize, f: impl FnOnce(&UnsafeCell<()>) -> R) -> R {
+    let mut cells = lock(&CELLS);
+    let cell = cells
+        .entry(addr)
+        .or_insert_with(|| Box::new(UnsafeCell::new(())));
+    f(cell)
+}
+
+/// Records a read of the per-CPU data at `addr` in the current model.
+#[doc(hidden)]
+pub fn track_read(addr: usize) {
+    with_cell(addr, |cell| cell.with(|_| ()))
+}
+
+/// Records a write of the per-CPU data at `addr` in the current model.
+#[doc(hidden)]
+pub fn track_write(addr: usize) {
+    with_cell(addr, |cell| cell.with_mut(|_| ()))
+}
+
+/// Runs `f` with [`loom::model`](::loom::model), i.e., once for every possible
+/// interleaving of the loom threads spawned in it.
+///
+/// Every execution starts with the per-CPU data areas as they are when this
+/// function is called, and with the calling thread bound to CPU 0. The areas
+/// must have been initialized (e.g., by [`init`](crate::init)).
+///
+/// Models run one at a time, even if called from multiple threads.
+pub fn model<F>(f: F)
+where
+    F: Fn() + Sync + Send + 'static,
+{
+    let _model = lock(&MODEL);
+    let size = percpu_area_size();
+    let areas: Vec<Vec<u8>> = (0..percpu_area_num())
+        .map(|cpu_id| unsafe {
+            core::slice::from_raw_parts(percpu_area_base(cpu_id) as *const u8, size).to_vec()
+        })
+        .collect();
+    ::loom::model(move || {
+        lock(&CELLS).clear();
+        for (cpu_id, area) in areas.iter().enumerate() {
+            unsafe {
+                let base = percpu_area_base(cpu_id) as *mut u8;
+                core::ptr::copy_nonoverlapping(area.as_ptr(), base, size);
+            }
+        }
+        init_percpu_reg(0);
+        f();
+    });
+}
+
+/// Spawns a loom thread as a simulated CPU, which is bound to the per-CPU data
+/// area of `cpu_id` before running `f`.
+///
+/// It must be called in the closure of [`model`].
+pub fn spawn_on<F, T>(cpu_id: usize, f: F) -> ::loom::thread::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    ::loom::thread::spawn(move || {
+        init_percpu_reg(cpu_id);
+        f()
+    })
+}
@@ -14,6 +14,14 @@ pub fn percpu_area_num() -> usize {
     1
 }
 
+/// Returns whether the per-CPU data areas have been initialized.
+///
+/// Always returns `true` for "sp-naive" use.
+#[cfg(feature = "dynamic")]
+pub(crate) fn is_init() -> bool {
+    true
+}
+
 /// Returns the base address of the per-CPU data area on the given CPU.
 ///
 /// Always returns `0` for "sp-naive" use.
@@ -21,6 +29,14 @@ pub fn percpu_area_base(_cpu_id: usize) -> usize {
     0
 }
 
+/// Returns the address of the per-CPU data template.
+///
+/// Always returns `None` for "sp-naive" use, since the per-CPU data is
+/// accessed in place.
+pub(crate) fn percpu_template() -> Option<usize> {
+    None
+}
+
 /// Reads the architecture-specific per-CPU data register.
 ///
 /// Always returns `0` for "sp-naive" use.
@@ -52,6 +68,53 @@ pub fn init(_cpu_count: usize) -> usize {
     1
 }
 
+/// Initialize all per-CPU data areas.
+///
+/// Returns the number of areas initialized.
+///
+/// For "sp-naive" use it does nothing and returns `Ok(1)`.
+pub fn try_init(_cpu_count: usize) -> Result<usize, crate::InitError> {
+    Ok(1)
+}
+
+/// Initialize per-CPU data areas in the given memory.
+///
+/// Returns the number of areas initialized.
+///
+/// For "sp-naive" use it does nothing and returns `Ok(1)`.
+///
+/// # Safety
+///
+/// This function is marked as `unsafe` for consistency with non "sp-naive"
+/// implementations.
+pub unsafe fn init_with_memory(
+    _base: core::ptr::NonNull<u8>,
+    _len: usize,
+    _cpu_count: usize,
+) -> Result<usize, crate::InitError> {
+    Ok(1)
+}
+
+/// Initialize per-CPU data areas in the memory allocated by `alloc`.
+///
+/// Returns the number of areas initialized.
+///
+/// For "sp-naive" use it does nothing and returns `Ok(1)`.
+///
+/// # Safety
+///
+/// This function is marked as `unsafe` for consistency with non "sp-naive"
+/// implementations.
+pub unsafe fn init_with_allocator<F>(
+    _cpu_count: usize,
+    _alloc: F,
+) -> Result<usize, crate::InitError>
+where
+    F: FnMut(usize, core::alloc::Layout) -> Option<core::ptr::NonNull<u8>>,
+{
+    Ok(1)
+}
+
 #[macro_export]
 macro_rules! impl_percpu {
     ($impl:ty) => {};
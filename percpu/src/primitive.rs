@@ -0,0 +1,191 @@
+//! Primitive types supported by the fast per-CPU accessors.
+
+use core::{marker::PhantomData, ptr::NonNull};
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Primitive types that can be read and written with a single instruction
+/// relative to the per-CPU data register.
+///
+/// For per-CPU static variables defined with [`def_percpu`](crate::def_percpu)
+/// whose type implements this trait, `read_current`/`write_current` take the
+/// single-instruction fast path, no matter how the type is spelled (e.g.,
+/// `u32`, `core::primitive::u32`, or a type alias). Other types fall back to
+/// `Clone` and assignment.
+///
+/// It is implemented for `bool`, all integer types up to 64 bits, thin raw
+/// pointers, `NonNull<T>` and `Option<NonNull<T>>`. This trait is sealed and
+/// cannot be implemented outside of this crate.
+pub trait PerCpuPrimitive: Copy + sealed::Sealed {
+    /// Converts the value to its bit pattern, zero- or sign-extended to 64
+    /// bits.
+    #[doc(hidden)]
+    fn into_bits(self) -> u64;
+
+    /// Converts the lower `size_of::<Self>()` bytes of `bits` to the value.
+    ///
+    /// # Safety
+    ///
+    /// The truncated bit pattern must be a valid value of `Self`.
+    #[doc(hidden)]
+    unsafe fn from_bits(bits: u64) -> Self;
+}
+
+/// Integer types that support the per-CPU arithmetic and exchange operations
+/// (e.g., `add_current`, `cmpxchg_current`).
+///
+/// This trait is sealed and cannot be implemented outside of this crate.
+pub trait PerCpuInteger: PerCpuPrimitive {}
+
+macro_rules! impl_int {
+    ($($ty:ty),*) => {$(
+        impl sealed::Sealed for $ty {}
+
+        impl PerCpuPrimitive for $ty {
+            #[inline(always)]
+            fn into_bits(self) -> u64 {
+                self as u64
+            }
+
+            #[inline(always)]
+            unsafe fn from_bits(bits: u64) -> Self {
+                bits as $ty
+            }
+        }
+
+        impl PerCpuInteger for $ty {}
+    )*};
+}
+
+impl_int!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+impl sealed::Sealed for bool {}
+
+impl PerCpuPrimitive for bool {
+    #[inline(always)]
+    fn into_bits(self) -> u64 {
+        self as u64
+    }
+
+    #[inline(always)]
+    unsafe fn from_bits(bits: u64) -> Self {
+        bits as u8 != 0
+    }
+}
+
+impl<T> sealed::Sealed for *const T {}
+
+impl<T> PerCpuPrimitive for *const T {
+    #[inline(always)]
+    fn into_bits(self) -> u64 {
+        self as usize as u64
+    }
+
+    #[inline(always)]
+    unsafe fn from_bits(bits: u64) -> Self {
+        bits as usize as Self
+    }
+}
+
+impl<T> sealed::Sealed for *mut T {}
+
+impl<T> PerCpuPrimitive for *mut T {
+    #[inline(always)]
+    fn into_bits(self) -> u64 {
+        self as usize as u64
+    }
+
+    #[inline(always)]
+    unsafe fn from_bits(bits: u64) -> Self {
+        bits as usize as Self
+    }
+}
+
+impl<T> sealed::Sealed for NonNull<T> {}
+
+impl<T> PerCpuPrimitive for NonNull<T> {
+    #[inline(always)]
+    fn into_bits(self) -> u64 {
+        self.as_ptr() as usize as u64
+    }
+
+    #[inline(always)]
+    unsafe fn from_bits(bits: u64) -> Self {
+        NonNull::new_unchecked(bits as usize as *mut T)
+    }
+}
+
+impl<T> sealed::Sealed for Option<NonNull<T>> {}
+
+impl<T> PerCpuPrimitive for Option<NonNull<T>> {
+    #[inline(always)]
+    fn into_bits(self) -> u64 {
+        match self {
+            Some(ptr) => ptr.as_ptr() as usize as u64,
+            None => 0,
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn from_bits(bits: u64) -> Self {
+        NonNull::new(bits as usize as *mut T)
+    }
+}
+
+/// Selects the fast accessors for [`PerCpuPrimitive`] types at compile time, by
+/// autoref-based specialization on the concrete type of a per-CPU variable.
+///
+/// `(&Dispatch::<T>::new()).read(fast, slow)` resolves to
+/// [`DispatchPrimitive::read`] if `T: PerCpuPrimitive`, which calls `fast`,
+/// and to [`DispatchOther::read`] otherwise, which calls `slow`.
+pub struct Dispatch<T>(PhantomData<T>);
+
+impl<T> Dispatch<T> {
+    /// Creates a dispatcher for the type `T`.
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+/// The fast path of [`Dispatch`], selected for [`PerCpuPrimitive`] types.
+pub trait DispatchPrimitive<T> {
+    /// Reads the value with `fast` if `T: PerCpuPrimitive`, or `slow` otherwise.
+    fn read(&self, fast: impl FnOnce() -> u64, slow: impl FnOnce() -> T) -> T;
+    /// Writes the value with `fast` if `T: PerCpuPrimitive`, or `slow` otherwise.
+    fn write(&self, val: T, fast: impl FnOnce(u64), slow: impl FnOnce(T));
+}
+
+impl<T: PerCpuPrimitive> DispatchPrimitive<T> for Dispatch<T> {
+    #[inline(always)]
+    fn read(&self, fast: impl FnOnce() -> u64, _slow: impl FnOnce() -> T) -> T {
+        unsafe { T::from_bits(fast()) }
+    }
+
+    #[inline(always)]
+    fn write(&self, val: T, fast: impl FnOnce(u64), _slow: impl FnOnce(T)) {
+        fast(val.into_bits())
+    }
+}
+
+/// The slow path of [`Dispatch`], selected for other types.
+pub trait DispatchOther<T> {
+    /// Reads the value with `fast` if `T: PerCpuPrimitive`, or `slow` otherwise.
+    fn read(&self, fast: impl FnOnce() -> u64, slow: impl FnOnce() -> T) -> T;
+    /// Writes the value with `fast` if `T: PerCpuPrimitive`, or `slow` otherwise.
+    fn write(&self, val: T, fast: impl FnOnce(u64), slow: impl FnOnce(T));
+}
+
+impl<T> DispatchOther<T> for &Dispatch<T> {
+    #[inline(always)]
+    fn read(&self, _fast: impl FnOnce() -> u64, slow: impl FnOnce() -> T) -> T {
+        slow()
+    }
+
+    #[inline(always)]
+    fn write(&self, val: T, _fast: impl FnOnce(u64), slow: impl FnOnce(T)) {
+        slow(val)
+    }
+}
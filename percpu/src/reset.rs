@@ -0,0 +1,106 @@
+//! Resetting per-CPU data to the link-time template.
+//!
+//! The per-CPU data template is the `.percpu` section in the load image, which
+//! holds the initial values of all per-CPU static variables. It is available
+//! if the per-CPU data areas do not overlap it, e.g., they are placed by
+//! `init_with_memory` on bare metal, or the `custom-tp` backend uses other
+//! memory. Otherwise, `try_init` keeps a copy of it in an area after the last
+//! one if the linker script reserves it, or in an extra area allocated on
+//! Linux, where the template is zeroed like the areas.
+
+use core::{fmt, ptr, sync::atomic::Ordering};
+
+use crate::hotplug::CPU_OFFLINE;
+use crate::{percpu_area_base, percpu_area_num, percpu_area_size};
+
+/// Errors returned when resetting per-CPU data to the template.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetError {
+    /// The CPU ID is not less than [`percpu_area_num`].
+    InvalidCpuId,
+    /// The per-CPU data template is not available, since it is overlapped by
+    /// the per-CPU data areas and no copy of it is kept.
+    TemplateUnavailable,
+    /// The per-CPU data is allocated dynamically, so it has no initial value in
+    /// the template.
+    DynamicAllocation,
+}
+
+impl fmt::Display for ResetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidCpuId => write!(f, "invalid CPU ID"),
+            Self::TemplateUnavailable => write!(f, "per-CPU data template is unavailable"),
+            Self::DynamicAllocation => write!(f, "per-CPU data is allocated dynamically"),
+        }
+    }
+}
+
+/// Returns the address of the template, checking the CPU ID.
+pub(crate) fn template_for(cpu_id: usize) -> Result<usize, ResetError> {
+    if cpu_id >= percpu_area_num() {
+        return Err(ResetError::InvalidCpuId);
+    }
+    crate::imp::percpu_template().ok_or(ResetError::TemplateUnavailable)
+}
+
+/// Returns whether the per-CPU data at `offset` is in the dynamic area.
+pub(crate) fn is_dynamic(offset: usize) -> bool {
+    #[cfg(feature = "dynamic")]
+    return crate::dynamic::dynamic_area_range().contains(&offset);
+    #[cfg(not(feature = "dynamic"))]
+    {
+        let _ = offset;
+        false
+    }
+}
+
+/// Restores the per-CPU data area of the given CPU from the link-time
+/// template, e.g., when the CPU is reset by kexec or a hypervisor.
+///
+/// All per-CPU static variables of the CPU get their initial values back,
+/// including the CPU ID recorded by `init_percpu_reg`, which must be called
+/// again by the CPU. Dynamic per-CPU allocations and whether the CPU is online
+/// are kept.
+///
+/// # Errors
+///
+/// - [`ResetError::InvalidCpuId`] if `cpu_id` is not less than
+///   [`percpu_area_num`].
+/// - [`ResetError::TemplateUnavailable`] if the template is overlapped by the
+///   per-CPU data areas and no copy of it is kept.
+///
+/// # Safety
+///
+/// The per-CPU data of the CPU must not be accessed during the call. The old
+/// values are overwritten without being dropped.
+pub unsafe fn reset_area(cpu_id: usize) -> Result<(), ResetError> {
+    let template = template_for(cpu_id)?;
+    let offline = CPU_OFFLINE.remote_ref_raw(cpu_id).load(Ordering::Acquire);
+    copy_template(template, cpu_id);
+    CPU_OFFLINE
+        .remote_ref_raw(cpu_id)
+        .store(offline, Ordering::Release);
+    Ok(())
+}
+
+/// Copies the per-CPU data template to the area of the given CPU, except the
+/// dynamic area.
+pub(crate) unsafe fn copy_template(template: usize, cpu_id: usize) {
+    let copy = |range: core::ops::Range<usize>| {
+        ptr::copy_nonoverlapping(
+            (template + range.start) as *const u8,
+            (percpu_area_base(cpu_id) + range.start) as *mut u8,
+            range.len(),
+        )
+    };
+    let size = percpu_area_size();
+    #[cfg(feature = "dynamic")]
+    {
+        let dynamic = crate::dynamic::dynamic_area_range();
+        copy(0..dynamic.start);
+        copy(dynamic.end..size);
+    }
+    #[cfg(not(feature = "dynamic"))]
+    copy(0..size);
+}
@@ -0,0 +1,418 @@
+//! Linux userspace backend based on restartable sequences (rseq).
+//!
+//! The per-CPU data area of the current thread follows the CPU that it is
+//! running on, which is read from the rseq area registered by glibc (2.35 or
+//! later) for every thread, so no per-CPU data register is used.
+//! `add_current`, `fetch_add_current`, `cmpxchg_current` and the primitive
+//! reads and writes of `read_current_raw`/`write_current_raw` are rseq critical
+//! sections, which are restarted if the thread is preempted or migrated, so
+//! they need neither locked instructions nor disabling preemption.
+//!
+//! If rseq is not registered (e.g., disabled by
+//! `GLIBC_TUNABLES=glibc.pthread.rseq=0`), the CPU is read with `getcpu` and
+//! the operations fall back to atomic instructions.
+
+use core::ptr::{self, addr_of};
+use core::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use crate::{percpu_area_base, percpu_area_num};
+
+/// The head of `struct rseq` in the Linux UAPI.
+#[repr(C)]
+struct Rseq {
+    cpu_id_start: u32,
+    cpu_id: u32,
+    rseq_cs: u64,
+    flags: u32,
+}
+
+extern "C" {
+    static __rseq_offset: isize;
+    static __rseq_size: u32;
+    fn get_nprocs_conf() -> i32;
+}
+
+/// Returns the rseq area of the current thread registered by glibc, or `None`
+/// if rseq is not registered.
+#[inline]
+fn rseq_area() -> Option<*const Rseq> {
+    if unsafe { __rseq_size } == 0 {
+        return None;
+    }
+    let tp: usize;
+    unsafe { core::arch::asm!("mov {}, fs:0", out(reg) tp) };
+    Some((tp as isize + unsafe { __rseq_offset }) as *const Rseq)
+}
+
+fn getcpu() -> usize {
+    const SYS_GETCPU: usize = 309;
+    let mut cpu = 0u32;
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") SYS_GETCPU => _,
+            in("rdi") &mut cpu as *mut u32,
+            in("rsi") 0,
+            in("rdx") 0,
+            lateout("rcx") _,
+            lateout("r11") _,
+        );
+    }
+    cpu as usize
+}
+
+/// Returns the ID of the CPU that the current thread is running on.
+#[inline]
+pub fn current_cpu() -> usize {
+    match rseq_area() {
+        Some(rseq) => unsafe { ptr::read_volatile(addr_of!((*rseq).cpu_id_start)) as usize },
+        None => getcpu(),
+    }
+}
+
+/// Returns the number of CPUs configured in the system, which the current
+/// thread may run on.
+pub(crate) fn configured_cpu_num() -> usize {
+    unsafe { get_nprocs_conf() }.max(1) as usize
+}
+
+/// Returns the address of the per-CPU data at `offset` on the given CPU.
+#[inline]
+fn area_ptr(cpu_id: usize, offset: usize) -> usize {
+    assert!(
+        cpu_id < percpu_area_num(),
+        "no per-CPU data area for CPU {}",
+        cpu_id
+    );
+    percpu_area_base(cpu_id) + offset
+}
+
+/// Returns the base address of the per-CPU data area on the CPU that the
+/// current thread is running on.
+#[inline]
+pub fn current_area_base() -> usize {
+    area_ptr(current_cpu(), 0)
+}
+
+/// Unsigned integers that can be updated in rseq critical sections.
+pub trait RseqInt: Copy + Eq {
+    /// Adds `val` to `*ptr` if the thread is still on `cpu_id` at the commit,
+    /// and returns the previous value. Returns `None` if the critical section
+    /// is aborted.
+    #[doc(hidden)]
+    unsafe fn rseq_fetch_add(
+        rseq: *const u8,
+        cpu_id: u32,
+        ptr: *mut Self,
+        val: Self,
+    ) -> Option<Self>;
+
+    /// Replaces `*ptr` with `new` if it is equal to `old` and the thread is
+    /// still on `cpu_id` at the commit, and returns the previous value. Returns
+    /// `None` if the critical section is aborted.
+    #[doc(hidden)]
+    unsafe fn rseq_cmpxchg(
+        rseq: *const u8,
+        cpu_id: u32,
+        ptr: *mut Self,
+        old: Self,
+        new: Self,
+    ) -> Option<Self>;
+
+    /// Reads `*ptr` if the thread is still on `cpu_id` at the load. Returns
+    /// `None` if the critical section is aborted.
+    #[doc(hidden)]
+    unsafe fn rseq_load(rseq: *const u8, cpu_id: u32, ptr: *const Self) -> Option<Self>;
+
+    /// Writes `val` to `*ptr` if the thread is still on `cpu_id` at the commit.
+    /// Returns `false` if the critical section is aborted.
+    #[doc(hidden)]
+    unsafe fn rseq_store(rseq: *const u8, cpu_id: u32, ptr: *mut Self, val: Self) -> bool;
+
+    #[doc(hidden)]
+    unsafe fn atomic_fetch_add(ptr: *mut Self, val: Self) -> Self;
+
+    #[doc(hidden)]
+    unsafe fn atomic_cmpxchg(ptr: *mut Self, old: Self, new: Self) -> Result<Self, Self>;
+}
+
+// The critical section descriptor (`struct rseq_cs`) is placed in `__rseq_cs`,
+// and the abort handler in `__rseq_failure`, preceded by the signature that
+// glibc registers rseq with (`RSEQ_SIG`), encoded in a `ud1` instruction. The
+// kernel restarts the critical section at the abort handler if the thread is
+// preempted or migrated before the commit instruction completes.
+macro_rules! impl_rseq_int {
+    ($ty:ty, $atomic:ty, $ptr:literal, $class:ident, $m:literal) => {
+        impl RseqInt for $ty {
+            #[inline]
+            unsafe fn rseq_fetch_add(
+                rseq: *const u8,
+                cpu_id: u32,
+                ptr: *mut Self,
+                val: Self,
+            ) -> Option<Self> {
+                let mut value = val;
+                let ok: u32;
+                core::arch::asm!(
+                    "lea {tmp}, [rip + 4f]",
+                    "mov qword ptr [{rseq} + 8], {tmp}",
+                    "mov {ok:e}, 1",
+                    "2:",
+                    "cmp dword ptr [{rseq} + 4], {cpu:e}",
+                    "jnz 5f",
+                    concat!("xadd ", $ptr, " ptr [{ptr}], {value", $m, "}"),
+                    "3:",
+                    ".pushsection __rseq_cs, \"aw\"",
+                    ".balign 32",
+                    "4:",
+                    ".long 0, 0",
+                    ".quad 2b, 3b - 2b, 5f",
+                    ".popsection",
+                    ".pushsection __rseq_failure, \"ax\"",
+                    ".byte 0x0f, 0xb9, 0x3d",
+                    ".long 0x53053053",
+                    "5:",
+                    "xor {ok:e}, {ok:e}",
+                    "jmp 3b",
+                    ".popsection",
+                    rseq = in(reg) rseq,
+                    cpu = in(reg) cpu_id,
+                    ptr = in(reg) ptr,
+                    value = inout($class) value,
+                    tmp = out(reg) _,
+                    ok = out(reg) ok,
+                    options(nostack),
+                );
+                (ok != 0).then_some(value)
+            }
+
+            #[inline]
+            unsafe fn rseq_cmpxchg(
+                rseq: *const u8,
+                cpu_id: u32,
+                ptr: *mut Self,
+                old: Self,
+                new: Self,
+            ) -> Option<Self> {
+                let prev: Self;
+                let ok: u32;
+                core::arch::asm!(
+                    "lea {tmp}, [rip + 4f]",
+                    "mov qword ptr [{rseq} + 8], {tmp}",
+                    "mov {ok:e}, 1",
+                    "2:",
+                    "cmp dword ptr [{rseq} + 4], {cpu:e}",
+                    "jnz 5f",
+                    concat!("mov {prev", $m, "}, ", $ptr, " ptr [{ptr}]"),
+                    concat!("cmp {prev", $m, "}, {old", $m, "}"),
+                    "jne 3f",
+                    concat!("mov ", $ptr, " ptr [{ptr}], {new", $m, "}"),
+                    "3:",
+                    ".pushsection __rseq_cs, \"aw\"",
+                    ".balign 32",
+                    "4:",
+                    ".long 0, 0",
+                    ".quad 2b, 3b - 2b, 5f",
+                    ".popsection",
+                    ".pushsection __rseq_failure, \"ax\"",
+                    ".byte 0x0f, 0xb9, 0x3d",
+                    ".long 0x53053053",
+                    "5:",
+                    "xor {ok:e}, {ok:e}",
+                    "jmp 3b",
+                    ".popsection",
+                    rseq = in(reg) rseq,
+                    cpu = in(reg) cpu_id,
+                    ptr = in(reg) ptr,
+                    old = in($class) old,
+                    new = in($class) new,
+                    prev = out($class) prev,
+                    tmp = out(reg) _,
+                    ok = out(reg) ok,
+                    options(nostack),
+                );
+                (ok != 0).then_some(prev)
+            }
+
+            #[inline]
+            unsafe fn rseq_load(rseq: *const u8, cpu_id: u32, ptr: *const Self) -> Option<Self> {
+                let value: Self;
+                let ok: u32;
+                core::arch::asm!(
+                    "lea {tmp}, [rip + 4f]",
+                    "mov qword ptr [{rseq} + 8], {tmp}",
+                    "mov {ok:e}, 1",
+                    "2:",
+                    "cmp dword ptr [{rseq} + 4], {cpu:e}",
+                    "jnz 5f",
+                    concat!("mov {value", $m, "}, ", $ptr, " ptr [{ptr}]"),
+                    "3:",
+                    ".pushsection __rseq_cs, \"aw\"",
+                    ".balign 32",
+                    "4:",
+                    ".long 0, 0",
+                    ".quad 2b, 3b - 2b, 5f",
+                    ".popsection",
+                    ".pushsection __rseq_failure, \"ax\"",
+                    ".byte 0x0f, 0xb9, 0x3d",
+                    ".long 0x53053053",
+                    "5:",
+                    "xor {ok:e}, {ok:e}",
+                    "jmp 3b",
+                    ".popsection",
+                    rseq = in(reg) rseq,
+                    cpu = in(reg) cpu_id,
+                    ptr = in(reg) ptr,
+                    value = out($class) value,
+                    tmp = out(reg) _,
+                    ok = out(reg) ok,
+                    options(nostack),
+                );
+                (ok != 0).then_some(value)
+            }
+
+            #[inline]
+            unsafe fn rseq_store(rseq: *const u8, cpu_id: u32, ptr: *mut Self, val: Self) -> bool {
+                let ok: u32;
+                core::arch::asm!(
+                    "lea {tmp}, [rip + 4f]",
+                    "mov qword ptr [{rseq} + 8], {tmp}",
+                    "mov {ok:e}, 1",
+                    "2:",
+                    "cmp dword ptr [{rseq} + 4], {cpu:e}",
+                    "jnz 5f",
+                    concat!("mov ", $ptr, " ptr [{ptr}], {val", $m, "}"),
+                    "3:",
+                    ".pushsection __rseq_cs, \"aw\"",
+                    ".balign 32",
+                    "4:",
+                    ".long 0, 0",
+                    ".quad 2b, 3b - 2b, 5f",
+                    ".popsection",
+                    ".pushsection __rseq_failure, \"ax\"",
+                    ".byte 0x0f, 0xb9, 0x3d",
+                    ".long 0x53053053",
+                    "5:",
+                    "xor {ok:e}, {ok:e}",
+                    "jmp 3b",
+                    ".popsection",
+                    rseq = in(reg) rseq,
+                    cpu = in(reg) cpu_id,
+                    ptr = in(reg) ptr,
+                    val = in($class) val,
+                    tmp = out(reg) _,
+                    ok = out(reg) ok,
+                    options(nostack),
+                );
+                ok != 0
+            }
+
+            #[inline]
+            unsafe fn atomic_fetch_add(ptr: *mut Self, val: Self) -> Self {
+                (*(ptr as *const $atomic)).fetch_add(val, Ordering::Relaxed)
+            }
+
+            #[inline]
+            unsafe fn atomic_cmpxchg(ptr: *mut Self, old: Self, new: Self) -> Result<Self, Self> {
+                (*(ptr as *const $atomic)).compare_exchange(
+                    old,
+                    new,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+            }
+        }
+    };
+}
+
+impl_rseq_int!(u8, AtomicU8, "byte", reg_byte, "");
+impl_rseq_int!(u16, AtomicU16, "word", reg, ":x");
+impl_rseq_int!(u32, AtomicU32, "dword", reg, ":e");
+impl_rseq_int!(u64, AtomicU64, "qword", reg, ":r");
+impl_rseq_int!(usize, AtomicUsize, "qword", reg, ":r");
+
+/// Reads the per-CPU data at `offset` on the current CPU.
+///
+/// The load is an rseq critical section, so it reads the area of the CPU that
+/// the thread is running on when the load completes.
+///
+/// # Safety
+///
+/// `offset` must be the offset of a per-CPU variable of type `T`.
+#[inline]
+pub unsafe fn read_current<T: RseqInt>(offset: usize) -> T {
+    let Some(rseq) = rseq_area() else {
+        return ptr::read_volatile(area_ptr(getcpu(), offset) as *const T);
+    };
+    loop {
+        let cpu_id = ptr::read_volatile(addr_of!((*rseq).cpu_id_start));
+        let ptr = area_ptr(cpu_id as usize, offset) as *const T;
+        if let Some(value) = T::rseq_load(rseq as *const u8, cpu_id, ptr) {
+            return value;
+        }
+    }
+}
+
+/// Writes `val` to the per-CPU data at `offset` on the current CPU.
+///
+/// The store is an rseq critical section, so it writes the area of the CPU
+/// that the thread is running on when the store completes.
+///
+/// # Safety
+///
+/// `offset` must be the offset of a per-CPU variable of type `T`.
+#[inline]
+pub unsafe fn write_current<T: RseqInt>(offset: usize, val: T) {
+    let Some(rseq) = rseq_area() else {
+        return ptr::write_volatile(area_ptr(getcpu(), offset) as *mut T, val);
+    };
+    loop {
+        let cpu_id = ptr::read_volatile(addr_of!((*rseq).cpu_id_start));
+        let ptr = area_ptr(cpu_id as usize, offset) as *mut T;
+        if T::rseq_store(rseq as *const u8, cpu_id, ptr, val) {
+            return;
+        }
+    }
+}
+
+/// Adds `val` to the per-CPU data at `offset` on the current CPU, and returns
+/// the previous value.
+///
+/// # Safety
+///
+/// `offset` must be the offset of a per-CPU variable of type `T`.
+#[inline]
+pub unsafe fn fetch_add_current<T: RseqInt>(offset: usize, val: T) -> T {
+    let Some(rseq) = rseq_area() else {
+        return T::atomic_fetch_add(area_ptr(getcpu(), offset) as *mut T, val);
+    };
+    loop {
+        let cpu_id = ptr::read_volatile(addr_of!((*rseq).cpu_id_start));
+        let ptr = area_ptr(cpu_id as usize, offset) as *mut T;
+        if let Some(prev) = T::rseq_fetch_add(rseq as *const u8, cpu_id, ptr, val) {
+            return prev;
+        }
+    }
+}
+
+/// Replaces the per-CPU data at `offset` on the current CPU with `new` if it
+/// is equal to `old`, like `compare_exchange` of atomic types.
+///
+/// # Safety
+///
+/// `offset` must be the offset of a per-CPU variable of type `T`.
+#[inline]
+pub unsafe fn cmpxchg_current<T: RseqInt>(offset: usize, old: T, new: T) -> Result<T, T> {
+    let Some(rseq) = rseq_area() else {
+        return T::atomic_cmpxchg(area_ptr(getcpu(), offset) as *mut T, old, new);
+    };
+    loop {
+        let cpu_id = ptr::read_volatile(addr_of!((*rseq).cpu_id_start));
+        let ptr = area_ptr(cpu_id as usize, offset) as *mut T;
+        match T::rseq_cmpxchg(rseq as *const u8, cpu_id, ptr, old, new) {
+            Some(prev) if prev == old => return Ok(prev),
+            Some(prev) => return Err(prev),
+            None => continue,
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Snapshots of per-CPU data areas, e.g., to move the per-CPU data of a vCPU
+//! to another physical CPU, or to keep it across suspend and resume.
+//!
+//! A snapshot is a header followed by a copy of the per-CPU data area. The
+//! header records the area size and the layout hash of the per-CPU static
+//! variables, so that a snapshot taken from a different build is rejected by
+//! [`restore_area`] instead of corrupting the per-CPU data.
+//!
+//! Restoring a snapshot taken by another build is not supported. The layout
+//! hash only covers the variables and the outline of their types (names, sizes
+//! and alignments), so it cannot tell that the fields of a type have changed
+//! while its size stays the same. Snapshots are meant to be
+//! restored by the same program, e.g., after suspend or on another CPU.
+
+use core::{fmt, ptr, sync::atomic::Ordering};
+
+use crate::hotplug::CPU_OFFLINE;
+use crate::{percpu_area_base, percpu_area_num, percpu_area_size, PerCpu};
+
+/// The layout of a per-CPU static variable, generated by `def_percpu` in the
+/// `percpu_layout` section.
+#[doc(hidden)]
+#[repr(C)]
+pub struct LayoutEntry {
+    /// The hash of the name and the type of the variable, as spelled in
+    /// `def_percpu`.
+    pub id: u64,
+    /// The size of the variable.
+    pub size: usize,
+    /// The alignment of the variable.
+    pub align: usize,
+    /// Returns the full name of the type of the variable, which resolves type
+    /// aliases and paths.
+    pub type_name: fn() -> &'static str,
+    /// The address of the variable in the `.percpu` section.
+    pub data: *const (),
+}
+
+unsafe impl Sync for LayoutEntry {}
+
+const SNAPSHOT_MAGIC: [u8; 4] = *b"PCPU";
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// The size of the header at the start of a snapshot.
+pub const SNAPSHOT_HEADER_SIZE: usize = 24;
+
+/// Errors returned when taking or restoring a snapshot of a per-CPU data area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// The CPU ID is not less than [`percpu_area_num`].
+    InvalidCpuId,
+    /// The buffer is smaller than the snapshot.
+    BufferTooSmall {
+        /// The size of the snapshot, see [`snapshot_size`].
+        required: usize,
+        /// The size of the buffer.
+        provided: usize,
+    },
+    /// The buffer does not start with a snapshot header of this version.
+    InvalidHeader,
+    /// The snapshot was taken from a build with a different per-CPU data
+    /// layout.
+    LayoutMismatch,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidCpuId => write!(f, "invalid CPU ID"),
+            Self::BufferTooSmall { required, provided } => write!(
+                f,
+                "buffer too small for the snapshot: {} < {} bytes",
+                provided, required
+            ),
+            Self::InvalidHeader => write!(f, "invalid snapshot header"),
+            Self::LayoutMismatch => write!(f, "per-CPU data layout mismatch"),
+        }
+    }
+}
+
+/// Returns the entries in the `percpu_layout` section, and the address of
+/// `_percpu_load_start` to compute their offsets.
+#[cfg(not(any(feature = "sp-naive", target_os = "macos", windows)))]
+fn layout_entries() -> (&'static [LayoutEntry], usize) {
+    extern "C" {
+        fn __start_percpu_layout();
+        fn __stop_percpu_layout();
+        fn _percpu_load_start();
+    }
+    let start = __start_percpu_layout as *const () as usize;
+    let end = __stop_percpu_layout as *const () as usize;
+    let len = (end - start) / core::mem::size_of::<LayoutEntry>();
+    let entries = unsafe { core::slice::from_raw_parts(start as *const LayoutEntry, len) };
+    (entries, _percpu_load_start as *const () as usize)
+}
+
+/// There is no `percpu_layout` section for "sp-naive" use, and section start
+/// and stop symbols are not available on Mach-O and PE targets.
+#[cfg(any(feature = "sp-naive", target_os = "macos", windows))]
+fn layout_entries() -> (&'static [LayoutEntry], usize) {
+    (&[], 0)
+}
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+/// Returns the hash of the layout of all per-CPU static variables in the
+/// program, i.e., their names, types (as spelled and as resolved by
+/// [`core::any::type_name`]), sizes, alignments and offsets.
+///
+/// It does not depend on the link order of the variables. It is a constant
+/// for "sp-naive" use, and on macOS and Windows, where only the area size is
+/// checked when restoring a snapshot.
+///
+/// Equal hashes do not guarantee that the types have the same fields, and
+/// [`core::any::type_name`] may differ between compiler versions, so it only
+/// guards against restoring a snapshot of another build by mistake.
+pub fn percpu_layout_hash() -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    let (entries, load_start) = layout_entries();
+    entries.iter().fold(FNV_OFFSET, |acc, entry| {
+        let offset = entry.data as usize - load_start;
+        let hash = fnv1a(FNV_OFFSET, &entry.id.to_le_bytes());
+        let hash = fnv1a(hash, &(offset as u64).to_le_bytes());
+        let hash = fnv1a(hash, &(entry.size as u64).to_le_bytes());
+        let hash = fnv1a(hash, &(entry.align as u64).to_le_bytes());
+        let hash = fnv1a(hash, (entry.type_name)().as_bytes());
+        acc.wrapping_add(hash)
+    })
+}
+
+/// Returns the size of a snapshot of a per-CPU data area, i.e., the header and
+/// the area.
+pub fn snapshot_size() -> usize {
+    SNAPSHOT_HEADER_SIZE + percpu_area_size()
+}
+
+/// The built-in per-CPU data that belongs to the physical CPU rather than to
+/// what runs on it, which is kept by [`restore_area`].
+struct CpuBound {
+    offline: bool,
+    #[cfg(not(feature = "rseq"))]
+    cpu_id: usize,
+    #[cfg(all(
+        target_arch = "x86_64",
+        not(any(feature = "sp-naive", feature = "custom-tp"))
+    ))]
+    self_ptr: usize,
+}
+
+impl CpuBound {
+    unsafe fn save(cpu_id: usize) -> Self {
+        Self {
+            offline: PerCpu::remote_ref_raw(&CPU_OFFLINE, cpu_id).load(Ordering::Acquire),
+            #[cfg(not(feature = "rseq"))]
+            cpu_id: *PerCpu::remote_ptr(&crate::cpu_id::CPU_ID, cpu_id),
+            #[cfg(all(
+                target_arch = "x86_64",
+                not(any(feature = "sp-naive", feature = "custom-tp"))
+            ))]
+            self_ptr: *PerCpu::remote_ptr(&crate::imp::SELF_PTR, cpu_id),
+        }
+    }
+
+    unsafe fn restore(self, cpu_id: usize) {
+        PerCpu::remote_ref_raw(&CPU_OFFLINE, cpu_id).store(self.offline, Ordering::Release);
+        #[cfg(not(feature = "rseq"))]
+        {
+            *(PerCpu::remote_ptr(&crate::cpu_id::CPU_ID, cpu_id) as *mut usize) = self.cpu_id;
+        }
+        #[cfg(all(
+            target_arch = "x86_64",
+            not(any(feature = "sp-naive", feature = "custom-tp"))
+        ))]
+        {
+            *(PerCpu::remote_ptr(&crate::imp::SELF_PTR, cpu_id) as *mut usize) = self.self_ptr;
+        }
+    }
+}
+
+/// Saves the per-CPU data area of the given CPU to `buf`, and returns the size
+/// of the snapshot.
+///
+/// The whole area is saved, including dynamic per-CPU allocations, which are
+/// only meaningful in the same running program.
+///
+/// # Errors
+///
+/// - [`SnapshotError::InvalidCpuId`] if `cpu_id` is not less than
+///   [`percpu_area_num`].
+/// - [`SnapshotError::BufferTooSmall`] if `buf` is smaller than
+///   [`snapshot_size`].
+///
+/// # Safety
+///
+/// The per-CPU data of the CPU must not be modified during the call.
+pub unsafe fn snapshot_area(cpu_id: usize, buf: &mut [u8]) -> Result<usize, SnapshotError> {
+    if cpu_id >= percpu_area_num() {
+        return Err(SnapshotError::InvalidCpuId);
+    }
+    let required = snapshot_size();
+    if buf.len() < required {
+        return Err(SnapshotError::BufferTooSmall {
+            required,
+            provided: buf.len(),
+        });
+    }
+    let size = percpu_area_size();
+    buf[0..4].copy_from_slice(&SNAPSHOT_MAGIC);
+    buf[4..8].copy_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+    buf[8..16].copy_from_slice(&(size as u64).to_le_bytes());
+    buf[16..24].copy_from_slice(&percpu_layout_hash().to_le_bytes());
+    // there is no area for "sp-naive" use.
+    if size > 0 {
+        ptr::copy_nonoverlapping(
+            percpu_area_base(cpu_id) as *const u8,
+            buf[SNAPSHOT_HEADER_SIZE..].as_mut_ptr(),
+            size,
+        );
+    }
+    Ok(required)
+}
+
+/// Restores the per-CPU data area of the given CPU from a snapshot taken by
+/// [`snapshot_area`], possibly on another CPU.
+///
+/// The CPU ID recorded by `init_percpu_reg`, whether the CPU is online, and
+/// other built-in per-CPU data that belongs to the CPU are kept.
+///
+/// # Errors
+///
+/// - [`SnapshotError::InvalidCpuId`] if `cpu_id` is not less than
+///   [`percpu_area_num`].
+/// - [`SnapshotError::InvalidHeader`] if `snapshot` does not start with a
+///   valid header.
+/// - [`SnapshotError::LayoutMismatch`] if the area size or the layout hash in
+///   the header differs from this build (see [`percpu_layout_hash`]).
+/// - [`SnapshotError::BufferTooSmall`] if `snapshot` is truncated.
+///
+/// # Safety
+///
+/// The per-CPU data of the CPU must not be accessed during the call. The old
+/// values are overwritten without being dropped, and the restored values must
+/// not be owned by the per-CPU data of another CPU, e.g., a snapshot must not
+/// be restored twice if the per-CPU data owns heap memory.
+pub unsafe fn restore_area(cpu_id: usize, snapshot: &[u8]) -> Result<(), SnapshotError> {
+    if cpu_id >= percpu_area_num() {
+        return Err(SnapshotError::InvalidCpuId);
+    }
+    let header = snapshot
+        .get(..SNAPSHOT_HEADER_SIZE)
+        .ok_or(SnapshotError::InvalidHeader)?;
+    let field = |range: core::ops::Range<usize>| {
+        let mut bytes = [0; 8];
+        bytes[..range.len()].copy_from_slice(&header[range]);
+        u64::from_le_bytes(bytes)
+    };
+    if header[0..4] != SNAPSHOT_MAGIC || field(4..8) != SNAPSHOT_VERSION as u64 {
+        return Err(SnapshotError::InvalidHeader);
+    }
+    let size = percpu_area_size();
+    if field(8..16) != size as u64 || field(16..24) != percpu_layout_hash() {
+        return Err(SnapshotError::LayoutMismatch);
+    }
+    let required = snapshot_size();
+    if snapshot.len() < required {
+        return Err(SnapshotError::BufferTooSmall {
+            required,
+            provided: snapshot.len(),
+        });
+    }
+    if size > 0 {
+        let cpu_bound = CpuBound::save(cpu_id);
+        ptr::copy_nonoverlapping(
+            snapshot[SNAPSHOT_HEADER_SIZE..].as_ptr(),
+            percpu_area_base(cpu_id) as *mut u8,
+            size,
+        );
+        cpu_bound.restore(cpu_id);
+    }
+    Ok(())
+}
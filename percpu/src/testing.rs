@@ -0,0 +1,264 @@
+//! Simulated multi-CPU environment for testing per-CPU code on a host.
+//!
+//! [`Simulator`] spawns one thread per simulated CPU, and binds each thread to
+//! the per-CPU data area of its CPU with [`init_percpu_reg`], so that the
+//! `*_current` accessors in the closures run by [`Simulator::run_on`] operate
+//! on the data of that CPU.
+//!
+//! The per-CPU data areas are laid out by the linker script, which the build
+//! script of this crate only passes to its own tests. The crate under test must
+//! link its test binaries with a copy of `test_percpu.x` from this repository,
+//! with the same arguments in its build script:
+//!
+//! ```text
+//! cargo:rustc-link-arg-tests=-no-pie
+//! cargo:rustc-link-arg-tests=-fuse-ld=bfd
+//! cargo:rustc-link-arg-tests=-T/path/to/test_percpu.x
+//! ```
+//!
+//! [`Simulator::new`] checks that the areas are available for the simulated
+//! CPUs, and panics with a hint to this setup otherwise.
+//!
+//! ```no_run
+//! # use percpu::{def_percpu, testing::Simulator};
+//! #[def_percpu]
+//! static COUNTER: usize = 0;
+//!
+//! // the per-CPU data areas are initialized for the 4 simulated CPUs.
+//! let sim = Simulator::new(4);
+//! sim.run_on_all(|cpu_id| COUNTER.write_current(cpu_id));
+//! assert_eq!(sim.run_on(2, || COUNTER.read_current()), 2);
+//! assert_eq!(COUNTER.sum_remote(), 0 + 1 + 2 + 3);
+//! ```
+
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Barrier};
+use std::thread::{self, JoinHandle};
+
+use crate::{init_percpu_reg, percpu_area_num, try_init, InitError};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Errors returned by [`Simulator::try_new`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimulatorError {
+    /// The CPU count is zero.
+    InvalidCpuCount,
+    /// The per-CPU data areas cannot be initialized, usually because the test
+    /// binary is not linked with the linker script (see the [module
+    /// documentation](self)).
+    Init(InitError),
+    /// The `.percpu` section is not linked at address 0, so the per-CPU data
+    /// cannot be accessed relative to the area base. The linker script of this
+    /// crate places it there.
+    MisplacedSection,
+    /// The per-CPU data areas have been initialized for fewer CPUs than
+    /// requested.
+    TooFewAreas {
+        /// The requested number of CPUs.
+        requested: usize,
+        /// The number of per-CPU data areas available.
+        available: usize,
+    },
+}
+
+impl fmt::Display for SimulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidCpuCount => write!(f, "invalid CPU count"),
+            Self::Init(e) => write!(
+                f,
+                "failed to initialize per-CPU data areas: {} (is the test binary linked with \
+                 `test_percpu.x`? see `percpu::testing`)",
+                e
+            ),
+            Self::MisplacedSection => write!(
+                f,
+                "the `.percpu` section is not linked at address 0 (is the test binary linked with \
+                 `test_percpu.x`? see `percpu::testing`)"
+            ),
+            Self::TooFewAreas {
+                requested,
+                available,
+            } => write!(
+                f,
+                "cannot simulate {} CPUs with {} per-CPU data areas",
+                requested, available
+            ),
+        }
+    }
+}
+
+/// A set of simulated CPUs, each of which is a thread bound to the per-CPU
+/// data area of its CPU.
+///
+/// The threads exit when the simulator is dropped.
+pub struct Simulator {
+    jobs: Vec<Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+    barrier: Arc<Barrier>,
+}
+
+impl Simulator {
+    /// Spawns `cpu_count` simulated CPUs, with IDs from `0` to `cpu_count - 1`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the per-CPU data areas are not available for `cpu_count` CPUs
+    /// (see [`try_new`](Self::try_new)), with a hint to the linker setup.
+    pub fn new(cpu_count: usize) -> Self {
+        match Self::try_new(cpu_count) {
+            Ok(sim) => sim,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Spawns `cpu_count` simulated CPUs, with IDs from `0` to `cpu_count - 1`.
+    ///
+    /// The per-CPU data areas are initialized for `cpu_count` CPUs with
+    /// [`try_init`] if they have not been initialized before. Otherwise,
+    /// `cpu_count` must not be greater than [`percpu_area_num`], e.g., when
+    /// another simulator has been created in the same test binary.
+    ///
+    /// # Errors
+    ///
+    /// - [`SimulatorError::InvalidCpuCount`] if `cpu_count` is 0.
+    /// - [`SimulatorError::MisplacedSection`] if the `.percpu` section is not
+    ///   linked at address 0 by the linker script.
+    /// - [`SimulatorError::Init`] if the areas cannot be initialized, e.g., the
+    ///   `.percpu` section is empty or reserves fewer areas, since the test
+    ///   binary is not linked with the linker script.
+    /// - [`SimulatorError::TooFewAreas`] if the areas have been initialized for
+    ///   fewer CPUs.
+    pub fn try_new(cpu_count: usize) -> Result<Self, SimulatorError> {
+        if cpu_count == 0 {
+            return Err(SimulatorError::InvalidCpuCount);
+        }
+        #[cfg(not(any(feature = "sp-naive", feature = "custom-tp", feature = "loom")))]
+        if !crate::imp::is_section_at_zero() {
+            return Err(SimulatorError::MisplacedSection);
+        }
+        match try_init(cpu_count) {
+            Ok(_) => {}
+            Err(InitError::AlreadyInitialized) if cpu_count > percpu_area_num() => {
+                return Err(SimulatorError::TooFewAreas {
+                    requested: cpu_count,
+                    available: percpu_area_num(),
+                })
+            }
+            Err(InitError::AlreadyInitialized) => {}
+            Err(e) => return Err(SimulatorError::Init(e)),
+        }
+        let (jobs, threads) = (0..cpu_count)
+            .map(|cpu_id| {
+                let (tx, rx) = mpsc::channel::<Job>();
+                let thread = thread::Builder::new()
+                    .name(format!("cpu{}", cpu_id))
+                    .spawn(move || {
+                        init_percpu_reg(cpu_id);
+                        for job in rx {
+                            job();
+                        }
+                    })
+                    .expect("failed to spawn a simulated CPU");
+                (tx, thread)
+            })
+            .unzip();
+        Ok(Self {
+            jobs,
+            threads,
+            barrier: Arc::new(Barrier::new(cpu_count)),
+        })
+    }
+
+    /// Returns the number of simulated CPUs.
+    pub fn cpu_count(&self) -> usize {
+        self.jobs.len()
+    }
+
+    /// Returns a barrier shared by all simulated CPUs, i.e., `wait` returns
+    /// once it has been called on every CPU.
+    ///
+    /// It is used to order the steps of closures running concurrently in
+    /// [`run_on_all`](Self::run_on_all).
+    pub fn barrier(&self) -> Arc<Barrier> {
+        self.barrier.clone()
+    }
+
+    /// Runs `f` on the given simulated CPU, and returns its result.
+    ///
+    /// If `f` panics, the panic is propagated to the caller, and the simulated
+    /// CPU keeps running.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cpu_id` is not less than [`cpu_count`](Self::cpu_count).
+    pub fn run_on<F, R>(&self, cpu_id: usize, f: F) -> R
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let result = self.spawn_on(cpu_id, f);
+        match result.recv().expect("simulated CPU exited") {
+            Ok(ret) => ret,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+
+    /// Runs `f(cpu_id)` on all simulated CPUs concurrently, and returns the
+    /// results in the order of CPU IDs.
+    ///
+    /// If `f` panics on any CPU, the first panic in the order of CPU IDs is
+    /// propagated to the caller after all CPUs have finished.
+    pub fn run_on_all<F, R>(&self, f: F) -> Vec<R>
+    where
+        F: Fn(usize) -> R + Send + Sync + 'static,
+        R: Send + 'static,
+    {
+        let f = Arc::new(f);
+        let results: Vec<_> = (0..self.cpu_count())
+            .map(|cpu_id| {
+                let f = f.clone();
+                self.spawn_on(cpu_id, move || f(cpu_id))
+            })
+            .collect();
+        let results: Vec<_> = results
+            .into_iter()
+            .map(|result| result.recv().expect("simulated CPU exited"))
+            .collect();
+        results
+            .into_iter()
+            .map(|result| result.unwrap_or_else(|payload| panic::resume_unwind(payload)))
+            .collect()
+    }
+
+    fn spawn_on<F, R>(&self, cpu_id: usize, f: F) -> mpsc::Receiver<thread::Result<R>>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        assert!(
+            cpu_id < self.cpu_count(),
+            "invalid simulated CPU ID {}",
+            cpu_id
+        );
+        let (tx, rx) = mpsc::channel();
+        let job = Box::new(move || {
+            let _ = tx.send(panic::catch_unwind(AssertUnwindSafe(f)));
+        });
+        self.jobs[cpu_id].send(job).expect("simulated CPU exited");
+        rx
+    }
+}
+
+impl Drop for Simulator {
+    fn drop(&mut self) {
+        // closing the channels makes the threads exit.
+        self.jobs.clear();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
@@ -4,6 +4,7 @@ SECTIONS
     .percpu : {
         _percpu_load_start = .;
         *(.percpu .percpu.*)
+        . = ALIGN(64);
         _percpu_load_end = .;
     }
 }
@@ -0,0 +1,40 @@
+#![cfg(all(target_os = "linux", feature = "dynamic", not(feature = "custom-tp")))]
+#![cfg(not(any(feature = "rseq", feature = "loom")))]
+
+use percpu::*;
+
+#[test]
+fn test_counter() {
+    init(4);
+    init_percpu_reg(0);
+
+    let counter = PerCpuCounter::new().unwrap();
+    counter.inc();
+    counter.add(10);
+    assert_eq!(counter.read(), 0);
+    assert_eq!(counter.sum(), 11);
+
+    #[cfg(not(feature = "sp-naive"))]
+    {
+        init_percpu_reg(1);
+        counter.add(5);
+        init_percpu_reg(0);
+        assert_eq!(counter.sum(), 16);
+    }
+
+    counter.reset_all();
+    assert_eq!(counter.sum(), 0);
+
+    // local deltas are folded into the shared count once they reach the batch
+    let counter = PerCpuCounter64::with_batch(8).unwrap();
+    counter.add(7);
+    assert_eq!((counter.read(), counter.sum()), (0, 7));
+    counter.inc();
+    assert_eq!((counter.read(), counter.sum()), (8, 8));
+    counter.add(100);
+    assert_eq!((counter.read(), counter.sum()), (108, 108));
+    counter.inc();
+    assert_eq!((counter.read(), counter.sum()), (108, 109));
+    counter.reset_all();
+    assert_eq!((counter.read(), counter.sum()), (0, 0));
+}
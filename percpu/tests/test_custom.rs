@@ -1,4 +1,5 @@
 #![cfg(all(feature = "custom-tp", any(target_os = "linux", target_os = "windows")))]
+#![cfg(not(feature = "loom"))]
 
 use percpu::*;
 
@@ -47,7 +48,7 @@ pub mod test_linux {
             let mut g = BASAE.lock().unwrap();
             *g = base as usize;
         }
-        init_data(CPU_COUNT);
+        percpu::init(CPU_COUNT);
 
         Mutex::new(HashMap::new())
     });
@@ -89,10 +90,15 @@ impl_percpu!(test_linux::PerCpuImpl);
 fn test_percpu() {
     extern crate std;
     test_linux::init();
+    assert_eq!(
+        try_init(test_linux::CPU_COUNT),
+        Err(InitError::AlreadyInitialized)
+    );
 
     for i in 0..test_linux::CPU_COUNT {
         let handle = std::thread::spawn(move || {
-            init(i);
+            init_percpu_reg(i);
+            assert_eq!(this_cpu_id(), i);
 
             assert_eq!(U8.read_current(), 1);
             assert_eq!(U16.read_current(), 2);
@@ -102,8 +108,76 @@ fn test_percpu() {
             U8.write_current(3);
 
             assert_eq!(U8.read_current(), 3);
+            assert_eq!(PerCpu::read_current(&U16), 2);
+            PerCpu::write_current(&U16, 4);
+            assert_eq!(U16.read_current(), 4);
+            U16.write_current_irqsave(5);
+            assert_eq!(*U16.current_irqsave(), 5);
+
+            let mut cpu = CpuGuard::new();
+            let val = U16.read(&cpu);
+            unsafe { U16.write(&mut cpu, val + 1) };
+            assert_eq!(*U16.get(&cpu), 6);
         });
 
         handle.join().unwrap();
     }
+
+    assert_eq!(U16.sum_remote(), 6 * test_linux::CPU_COUNT as u16);
+    assert_eq!(U16.try_read_remote(1), Some(6));
+    assert_eq!(U16.try_read_remote(test_linux::CPU_COUNT), None);
+    assert!(unsafe { STRUCT.try_remote_ref(test_linux::CPU_COUNT) }.is_none());
+    assert_eq!(
+        U8.fold_remote(0, |acc, v| acc + v as usize),
+        3 * test_linux::CPU_COUNT
+    );
+    assert!(unsafe { STRUCT.iter_remote() }
+        .enumerate()
+        .all(|(i, (cpu_id, s))| i == cpu_id && s.foo == 10));
+
+    // re-online a CPU with its area reset to the template.
+    assert_eq!(cpu_offline(1), Ok(()));
+    assert_eq!(cpu_online(1, true), Ok(()));
+    assert!(is_cpu_online(1));
+    std::thread::spawn(|| {
+        init_percpu_reg(1);
+        assert_eq!(U8.read_current(), 1);
+    })
+    .join()
+    .unwrap();
+
+    // reset a single variable or a whole area to the template.
+    unsafe { U8.write_remote(2, 9) };
+    assert_eq!(unsafe { U8.reset_remote(2) }, Ok(()));
+    assert_eq!(U8.read_remote(2), 1);
+    assert_eq!(
+        unsafe { U8.reset_remote(test_linux::CPU_COUNT) },
+        Err(ResetError::InvalidCpuId)
+    );
+    unsafe { U16.write_remote(3, 9) };
+    assert_eq!(cpu_offline(3), Ok(()));
+    assert_eq!(unsafe { reset_area(3) }, Ok(()));
+    assert_eq!(U16.read_remote(3), 2);
+    assert!(!is_cpu_online(3));
+    assert_eq!(cpu_online(3, false), Ok(()));
+
+    // move the data of a CPU to another with a snapshot.
+    unsafe { U16.write_remote(2, 7) };
+    let mut snapshot = vec![0; snapshot_size()];
+    assert_eq!(
+        unsafe { snapshot_area(2, &mut snapshot) },
+        Ok(snapshot_size())
+    );
+    assert_eq!(unsafe { restore_area(3, &snapshot) }, Ok(()));
+    assert_eq!(U16.read_remote(3), 7);
+    snapshot[16] ^= 1;
+    assert_eq!(
+        unsafe { restore_area(3, &snapshot) },
+        Err(SnapshotError::LayoutMismatch)
+    );
+
+    // calling `init` again copies the template to each area again.
+    percpu::init(test_linux::CPU_COUNT);
+    assert_eq!(U16.read_remote(3), 2);
+    assert_eq!(U8.read_remote(0), 1);
 }
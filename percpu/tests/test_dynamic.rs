@@ -0,0 +1,59 @@
+#![cfg(all(target_os = "linux", feature = "dynamic", not(feature = "custom-tp")))]
+#![cfg(not(any(feature = "rseq", feature = "loom")))]
+
+use percpu::*;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Queue {
+    head: usize,
+    tail: usize,
+}
+
+#[test]
+fn test_dynamic() {
+    #[cfg(not(feature = "sp-naive"))]
+    assert!(std::panic::catch_unwind(|| alloc_percpu(0u8)).is_err());
+    init(4);
+    init_percpu_reg(0);
+
+    let counter = alloc_percpu(0u64).unwrap();
+    let queue = alloc_percpu(Queue { head: 1, tail: 2 }).unwrap();
+    assert_ne!(counter.offset(), queue.offset());
+
+    counter.with_current(|c| *c += 10);
+    unsafe { queue.current_mut() }.head = 100;
+    assert_eq!(counter.read_current(), 10);
+    assert_eq!(queue.read_current(), Queue { head: 100, tail: 2 });
+
+    assert!(std::panic::catch_unwind(|| unsafe { counter.remote_ptr(percpu_area_num()) }).is_err());
+    for cpu_id in 1..percpu_area_num() {
+        assert_eq!(counter.read_remote(cpu_id), 0);
+        assert_eq!(queue.read_remote(cpu_id), Queue { head: 1, tail: 2 });
+        unsafe { counter.write_remote(cpu_id, cpu_id as u64) };
+    }
+
+    #[cfg(not(feature = "sp-naive"))]
+    {
+        init_percpu_reg(1);
+        assert_eq!(counter.read_current(), 1);
+        init_percpu_reg(0);
+    }
+
+    assert_eq!(
+        unsafe { counter.reset_remote(1) },
+        Err(ResetError::DynamicAllocation)
+    );
+
+    // freed slots are reused
+    let offset = counter.offset();
+    drop(counter);
+    let counter = alloc_percpu(0u32).unwrap();
+    assert_eq!(counter.offset(), offset);
+
+    // too large or over-aligned types are rejected
+    assert!(alloc_percpu([0u8; DYNAMIC_AREA_SIZE]).is_none());
+    #[derive(Clone)]
+    #[repr(align(128))]
+    struct OverAligned;
+    assert!(alloc_percpu(OverAligned).is_none());
+}
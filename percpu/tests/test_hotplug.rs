@@ -0,0 +1,82 @@
+#![cfg(all(
+    target_os = "linux",
+    not(feature = "sp-naive"),
+    not(feature = "custom-tp")
+))]
+#![cfg(not(feature = "loom"))]
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use percpu::*;
+
+#[def_percpu]
+static PENDING: usize = 0;
+
+static ONLINE_COUNT: AtomicUsize = AtomicUsize::new(0);
+static DRAINED: AtomicUsize = AtomicUsize::new(0);
+static LATE_ONLINE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+static PENDING_HOOK: HotplugHook = HotplugHook::new(
+    Some(|_| {
+        ONLINE_COUNT.fetch_add(1, Ordering::SeqCst);
+        // hooks can be registered from a hook.
+        register_hotplug_hook(&LATE_HOOK);
+    }),
+    Some(|cpu_id| {
+        let pending = core::mem::take(unsafe { PENDING.remote_ref_mut_raw(cpu_id) });
+        DRAINED.fetch_add(pending, Ordering::SeqCst);
+    }),
+);
+
+static LATE_HOOK: HotplugHook = HotplugHook::new(
+    Some(|_| {
+        LATE_ONLINE_COUNT.fetch_add(1, Ordering::SeqCst);
+    }),
+    None,
+);
+
+#[test]
+fn test_hotplug() {
+    assert_eq!(init(4), 4);
+    init_percpu_reg(0);
+    register_hotplug_hook(&PENDING_HOOK);
+    register_hotplug_hook(&PENDING_HOOK);
+
+    assert!((0..4).all(is_cpu_online));
+    assert!(!is_cpu_online(4));
+
+    unsafe { PENDING.write_remote(2, 10) };
+    assert_eq!(cpu_offline(2), Ok(()));
+    assert!(!is_cpu_online(2));
+    assert_eq!(DRAINED.load(Ordering::SeqCst), 10);
+    assert_eq!(PENDING.read_remote(2), 0);
+    assert_eq!(cpu_offline(2), Err(HotplugError::AlreadyOffline));
+    assert_eq!(cpu_offline(4), Err(HotplugError::InvalidCpuId));
+
+    // `.percpu` is not loaded on hosted targets, so the template kept by
+    // `init` is zeroed like the areas.
+    unsafe { PENDING.write_remote(2, 5) };
+    assert_eq!(cpu_online(2, true), Ok(()));
+    assert!(is_cpu_online(2));
+    assert_eq!(PENDING.read_remote(2), 0);
+    assert_eq!(ONLINE_COUNT.load(Ordering::SeqCst), 1);
+    assert_eq!(cpu_online(2, false), Err(HotplugError::AlreadyOnline));
+
+    unsafe { PENDING.write_remote(2, 7) };
+    assert_eq!(unsafe { PENDING.reset_remote(2) }, Ok(()));
+    assert_eq!(PENDING.read_remote(2), 0);
+    assert_eq!(
+        unsafe { PENDING.reset_remote(4) },
+        Err(ResetError::InvalidCpuId)
+    );
+    unsafe { PENDING.write_remote(2, 9) };
+    assert_eq!(unsafe { reset_area(2) }, Ok(()));
+    assert_eq!(PENDING.read_remote(2), 0);
+    // the CPU stays online.
+    assert!(is_cpu_online(2));
+
+    assert_eq!(cpu_offline(1), Ok(()));
+    assert_eq!(cpu_online(1, false), Ok(()));
+    assert_eq!(ONLINE_COUNT.load(Ordering::SeqCst), 2);
+    assert_eq!(LATE_ONLINE_COUNT.load(Ordering::SeqCst), 1);
+}
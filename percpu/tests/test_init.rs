@@ -0,0 +1,87 @@
+#![cfg(all(
+    target_os = "linux",
+    not(feature = "sp-naive"),
+    not(feature = "custom-tp")
+))]
+#![cfg(not(any(feature = "rseq", feature = "loom")))]
+
+use core::ptr::NonNull;
+
+use percpu::*;
+
+#[def_percpu]
+static VALUE: u64 = 0;
+
+#[test]
+fn test_init_with_memory() {
+    const CPU_COUNT: usize = 6;
+
+    let stride = (percpu_area_size() + 63) & !63;
+    let layout = std::alloc::Layout::from_size_align(stride * CPU_COUNT, 64).unwrap();
+    let base = NonNull::new(unsafe { std::alloc::alloc_zeroed(layout) }).unwrap();
+    let len = layout.size();
+
+    assert_eq!(try_init(0), Err(InitError::InvalidCpuCount));
+    assert_eq!(
+        try_init(usize::MAX),
+        Err(InitError::SectionTooSmall {
+            requested: usize::MAX,
+            reserved: 4
+        })
+    );
+
+    unsafe {
+        let misaligned = NonNull::new(base.as_ptr().add(8)).unwrap();
+        assert_eq!(
+            init_with_memory(misaligned, len - 8, CPU_COUNT),
+            Err(InitError::MisalignedMemory)
+        );
+        assert_eq!(
+            init_with_memory(base, len - 1, CPU_COUNT),
+            Err(InitError::MemoryTooSmall {
+                required: len,
+                provided: len - 1
+            })
+        );
+        assert_eq!(
+            init_with_memory(base, len, 0),
+            Err(InitError::InvalidCpuCount)
+        );
+        assert_eq!(init_with_memory(base, len, CPU_COUNT), Ok(CPU_COUNT));
+        assert_eq!(
+            init_with_memory(base, len, CPU_COUNT),
+            Err(InitError::AlreadyInitialized)
+        );
+    }
+    assert_eq!(init(4), 0);
+    assert_eq!(try_init(4), Err(InitError::AlreadyInitialized));
+    assert_eq!(
+        try_init(4).unwrap_err().to_string(),
+        "per-CPU data areas are already initialized"
+    );
+
+    assert_eq!(percpu_area_num(), CPU_COUNT);
+    for cpu_id in 0..CPU_COUNT {
+        assert_eq!(
+            percpu_area_base(cpu_id),
+            base.as_ptr() as usize + cpu_id * stride
+        );
+    }
+
+    init_percpu_reg(CPU_COUNT - 1);
+    assert_eq!(read_percpu_reg(), percpu_area_base(CPU_COUNT - 1));
+    VALUE.write_current(0xdead_beef);
+    assert_eq!(VALUE.read_remote(CPU_COUNT - 1), 0xdead_beef);
+    assert_eq!(VALUE.read_remote(0), 0);
+
+    // test the built-in CPU ID
+    assert_eq!(this_cpu_id(), CPU_COUNT - 1);
+    init_percpu_reg(2);
+    assert_eq!(this_cpu_id(), 2);
+    for cpu_id in 0..CPU_COUNT {
+        assert_eq!(cpu_id_of_area(percpu_area_base(cpu_id)), Some(cpu_id));
+    }
+    assert_eq!(cpu_id_of_area(percpu_area_base(0) - 1), None);
+    assert_eq!(cpu_id_of_area(percpu_area_base(1) + 8), None);
+    assert_eq!(cpu_id_of_area(percpu_area_base(CPU_COUNT)), None);
+}
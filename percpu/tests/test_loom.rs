@@ -0,0 +1,108 @@
+#![cfg(all(target_os = "linux", feature = "loom"))]
+
+#[cfg(not(feature = "custom-tp"))]
+use ::loom::sync::atomic::{AtomicBool, Ordering};
+#[cfg(not(feature = "custom-tp"))]
+use ::loom::sync::Arc;
+use percpu::*;
+
+#[def_percpu]
+static VALUE: usize = 0;
+
+#[cfg(not(feature = "custom-tp"))]
+#[test]
+fn test_loom_cpus() {
+    init(4);
+    percpu::loom::model(|| {
+        // every execution starts from the same per-CPU data.
+        assert_eq!(VALUE.read_remote(2), 0);
+        assert_eq!(this_cpu_id(), 0);
+        let cpu2 = percpu::loom::spawn_on(2, || {
+            VALUE.add_current(2);
+            this_cpu_id()
+        });
+        VALUE.write_current(1);
+        assert_eq!(cpu2.join().unwrap(), 2);
+        assert_eq!(VALUE.read_remote(0), 1);
+        assert_eq!(VALUE.read_remote(2), 2);
+    });
+}
+
+#[cfg(not(feature = "custom-tp"))]
+#[test]
+fn test_loom_synchronized() {
+    init(4);
+    percpu::loom::model(|| {
+        let ready = Arc::new(AtomicBool::new(false));
+        let ready2 = ready.clone();
+        let cpu1 = percpu::loom::spawn_on(1, move || {
+            unsafe { VALUE.write_remote(0, 10) };
+            ready2.store(true, Ordering::Release);
+        });
+        if ready.load(Ordering::Acquire) {
+            VALUE.with_current(|v| *v += 1);
+            assert_eq!(VALUE.read_current(), 11);
+        }
+        cpu1.join().unwrap();
+    });
+}
+
+#[cfg(not(feature = "custom-tp"))]
+#[test]
+#[should_panic(expected = "Causality violation")]
+fn test_loom_race() {
+    init(4);
+    percpu::loom::model(|| {
+        let cpu1 = percpu::loom::spawn_on(1, || unsafe { VALUE.write_remote(0, 10) });
+        VALUE.with_current(|v| *v += 1);
+        cpu1.join().unwrap();
+    });
+}
+
+#[cfg(feature = "custom-tp")]
+mod custom_tp {
+    use std::alloc::Layout;
+    use std::cell::Cell;
+    use std::ptr::NonNull;
+
+    use super::*;
+
+    static BASE: spin::Lazy<usize> = spin::Lazy::new(|| {
+        let layout = Layout::from_size_align(4 * percpu_section_size(), 64).unwrap();
+        unsafe { std::alloc::alloc_zeroed(layout) as usize }
+    });
+
+    ::loom::thread_local! {
+        // loom threads share an OS thread, so the register is a loom thread-local variable.
+        static CPU_LOCAL_REG: Cell<usize> = Cell::new(0);
+    }
+
+    pub struct LoomImpl;
+
+    impl Impl for LoomImpl {
+        fn percpu_base() -> NonNull<u8> {
+            NonNull::new(*BASE as *mut u8).unwrap()
+        }
+
+        fn set_cpu_local_ptr(ptr: *mut u8) {
+            CPU_LOCAL_REG.with(|reg| reg.set(ptr as usize));
+        }
+
+        fn get_cpu_local_ptr() -> *mut u8 {
+            CPU_LOCAL_REG.with(|reg| reg.get()) as *mut u8
+        }
+    }
+
+    impl_percpu!(LoomImpl);
+
+    #[test]
+    #[should_panic(expected = "Causality violation")]
+    fn test_loom_race() {
+        init(4);
+        percpu::loom::model(|| {
+            let cpu1 = percpu::loom::spawn_on(1, || unsafe { PerCpu::write_remote(&VALUE, 0, 10) });
+            VALUE.with_current(|v| *v += 1);
+            cpu1.join().unwrap();
+        });
+    }
+}
@@ -0,0 +1,75 @@
+#![cfg(all(
+    target_os = "linux",
+    not(feature = "sp-naive"),
+    not(feature = "custom-tp")
+))]
+#![cfg(not(any(feature = "rseq", feature = "loom")))]
+
+use std::alloc::Layout;
+use std::ptr::NonNull;
+
+use percpu::*;
+
+const CPU_COUNT: usize = 4;
+
+#[def_percpu]
+static VALUE: u64 = 0;
+
+fn alloc(layout: Layout) -> Option<NonNull<u8>> {
+    NonNull::new(unsafe { std::alloc::alloc(layout) })
+}
+
+#[test]
+fn test_init_with_allocator() {
+    unsafe {
+        assert_eq!(
+            init_with_allocator(0, |_, layout| alloc(layout)),
+            Err(InitError::InvalidCpuCount)
+        );
+        assert_eq!(
+            init_with_allocator(CPU_COUNT, |cpu_id, layout| (cpu_id < 2)
+                .then(|| alloc(layout))
+                .flatten()),
+            Err(InitError::AllocationFailed)
+        );
+        assert_eq!(
+            init_with_allocator(CPU_COUNT, |_, layout| alloc(layout)
+                .map(|ptr| NonNull::new_unchecked(ptr.as_ptr().add(1)))),
+            Err(InitError::MisalignedMemory)
+        );
+    }
+
+    // allocate each area separately with some padding, as if on different nodes.
+    let mut calls = Vec::new();
+    let res = unsafe {
+        init_with_allocator(CPU_COUNT, |cpu_id, layout| {
+            calls.push((cpu_id, layout.size()));
+            alloc(Layout::from_size_align(layout.size() + 0x100, layout.align()).unwrap())
+        })
+    };
+    assert_eq!(res, Ok(CPU_COUNT));
+    assert_eq!(init(CPU_COUNT), 0);
+    assert_eq!(calls.len(), CPU_COUNT + 1);
+    assert_eq!(calls[0], (0, CPU_COUNT * core::mem::size_of::<usize>()));
+    assert!(calls[1..]
+        .iter()
+        .enumerate()
+        .all(|(i, &(cpu_id, size))| i == cpu_id && size >= percpu_area_size()));
+
+    assert_eq!(percpu_area_num(), CPU_COUNT);
+    for cpu_id in 0..CPU_COUNT {
+        assert_eq!(percpu_area_base(cpu_id) % 64, 0);
+        assert_eq!(cpu_id_of_area(percpu_area_base(cpu_id)), Some(cpu_id));
+        assert_eq!(VALUE.read_remote(cpu_id), 0);
+        unsafe { VALUE.write_remote(cpu_id, cpu_id as u64 * 10) };
+    }
+    assert!(std::panic::catch_unwind(|| percpu_area_base(CPU_COUNT)).is_err());
+
+    init_percpu_reg(2);
+    assert_eq!(read_percpu_reg(), percpu_area_base(2));
+    assert_eq!(this_cpu_id(), 2);
+    assert_eq!(VALUE.read_current(), 20);
+    VALUE.write_current(21);
+    assert_eq!(VALUE.read_remote(2), 21);
+    assert_eq!(VALUE.sum_remote(), 61);
+}
@@ -1,6 +1,9 @@
 #![cfg(not(target_os = "macos"))]
+#![cfg(not(any(feature = "rseq", feature = "loom")))]
 #![cfg(not(feature = "custom-tp"))]
 
+use core::ptr::NonNull;
+
 use percpu::*;
 
 // Initial value is unsupported for testing.
@@ -23,6 +26,33 @@ static U64: u64 = 0;
 #[def_percpu]
 static USIZE: usize = 0;
 
+#[def_percpu]
+static I8: i8 = 0;
+
+#[def_percpu]
+static I32: i32 = 0;
+
+#[def_percpu]
+static ISIZE: isize = 0;
+
+#[def_percpu]
+static PTR: *mut Struct = core::ptr::null_mut();
+
+#[def_percpu]
+static NON_NULL: Option<NonNull<Struct>> = None;
+
+type Counter = u64;
+
+#[def_percpu]
+static ALIAS: Counter = 0;
+
+#[def_percpu]
+static QUALIFIED: core::primitive::u32 = 0;
+
+#[def_percpu(irq_safe)]
+static IRQ_SAFE: usize = 0;
+
+#[derive(Clone, Debug, PartialEq)]
 struct Struct {
     foo: usize,
     bar: u8,
@@ -41,7 +71,9 @@ fn test_percpu() {
 
     #[cfg(not(feature = "sp-naive"))]
     let base = {
-        assert_eq!(init(), 4);
+        // all areas reserved by the linker script are initialized.
+        assert_eq!(init(2), 4);
+        assert_eq!(percpu_area_num(), 4);
         unsafe { write_percpu_reg(percpu_area_base(0)) };
 
         let base = read_percpu_reg();
@@ -94,6 +126,65 @@ fn test_percpu() {
     assert_eq!(U64.read_current(), 0xa2ce_a2ce_a2ce_a2ce);
     assert_eq!(USIZE.read_current(), 0xffff_0000);
 
+    // test arithmetic operations
+    U8.add_current(10);
+    U16.sub_current(0xcd);
+    U32.inc_current();
+    U64.dec_current();
+    assert_eq!(USIZE.fetch_add_current(0x1), 0xffff_0000);
+
+    assert_eq!(U8.read_current(), 133);
+    assert_eq!(U16.read_current(), 0xab00);
+    assert_eq!(U32.read_current(), 0xdead_bef0);
+    assert_eq!(U64.read_current(), 0xa2ce_a2ce_a2ce_a2cd);
+    assert_eq!(USIZE.read_current(), 0xffff_0001);
+
+    U8.add_current(200);
+    assert_eq!(U8.read_current(), 77); // wrapping
+
+    // test compare-and-exchange and exchange operations
+    assert_eq!(U32.cmpxchg_current(0xdead_bef0, 1), Ok(0xdead_bef0));
+    assert_eq!(U32.cmpxchg_current(0xdead_bef0, 2), Err(1));
+    assert_eq!(U32.read_current(), 1);
+    assert_eq!(U8.cmpxchg_current(77, 78), Ok(77));
+    assert_eq!(U64.xchg_current(3), 0xa2ce_a2ce_a2ce_a2cd);
+    assert_eq!(U64.read_current(), 3);
+    assert_eq!(U16.xchg_current(4), 0xab00);
+    U8.write_current(123);
+    U16.write_current(0xabcd);
+    U32.write_current(0xdead_beef);
+    U64.write_current(0xa2ce_a2ce_a2ce_a2ce);
+    USIZE.write_current(0xffff_0000);
+
+    // test signed integer and pointer types
+    I8.write_current(-100);
+    I32.write_current(-0x1234_5678);
+    ISIZE.write_current(isize::MIN);
+    assert_eq!(I8.read_current(), -100);
+    assert_eq!(I32.read_current(), -0x1234_5678);
+    assert_eq!(ISIZE.read_current(), isize::MIN);
+
+    let mut task = Struct { foo: 1, bar: 2 };
+    assert!(PTR.read_current().is_null());
+    assert_eq!(NON_NULL.read_current(), None);
+    PTR.write_current(&mut task);
+    NON_NULL.write_current(Some(NonNull::from(&mut task)));
+    assert_eq!(PTR.read_current(), &mut task as *mut Struct);
+    assert_eq!(NON_NULL.read_current(), Some(NonNull::from(&mut task)));
+    NON_NULL.write_current(None);
+    assert_eq!(NON_NULL.read_current(), None);
+    PTR.write_current(core::ptr::null_mut());
+
+    // test types that are not spelled as primitive names
+    ALIAS.write_current(0x1234_5678_9abc);
+    ALIAS.add_current(1);
+    QUALIFIED.write_current(42);
+    assert_eq!(ALIAS.read_current(), 0x1234_5678_9abd);
+    assert_eq!(QUALIFIED.xchg_current(43), 42);
+    assert_eq!(QUALIFIED.read_current(), 43);
+    I32.sub_current(1);
+    assert_eq!(I32.read_current(), -0x1234_5679);
+
     STRUCT.with_current(|s| {
         println!("struct.foo value: {:#x}", s.foo);
         println!("struct.bar value: {}", s.bar);
@@ -101,10 +192,139 @@ fn test_percpu() {
         assert_eq!(s.bar, 100);
     });
 
+    // test read/write of non-primitive types
+    let old = STRUCT.read_current();
+    STRUCT.write_current(Struct {
+        foo: 0x4444,
+        bar: 44,
+    });
+    assert_eq!(
+        STRUCT.read_current(),
+        Struct {
+            foo: 0x4444,
+            bar: 44
+        }
+    );
+    STRUCT.write_current(old);
+
+    // test guard-returning accessors
+    {
+        let mut s = unsafe { STRUCT.current_mut() };
+        s.foo += 1;
+        s.bar += 1;
+        assert_eq!(struct_sum(&s), 0x2334 + 101);
+    }
+    assert_eq!(STRUCT.current().foo, 0x2334);
+    assert_eq!(*U32.current(), 0xdead_beef);
+    *unsafe { U32.current_mut() } = 0xdead_bef0;
+    assert_eq!(U32.read_current(), 0xdead_bef0);
+    STRUCT.with_current(|s| {
+        s.foo -= 1;
+        s.bar -= 1;
+    });
+    U32.write_current(0xdead_beef);
+
+    // test IRQ-saving accessors
+    IRQ_SAFE.write_current(1);
+    IRQ_SAFE.with_current_irqsave(|v| *v += 1);
+    *unsafe { IRQ_SAFE.current_mut() } += 1;
+    // generic code also takes the guard of `irq_safe`.
+    let v: PerCpuRefMut<'_, usize, IrqSaveGuard> = unsafe { PerCpu::current_mut(&IRQ_SAFE) };
+    drop(v);
+    assert_eq!(*IRQ_SAFE.current_irqsave(), 3);
+    assert_eq!(IRQ_SAFE.read_current_irqsave(), 3);
+    U16.write_current_irqsave(0x1234);
+    assert_eq!(U16.read_current_irqsave(), 0x1234);
+    unsafe { STRUCT.current_mut_irqsave() }.bar = 55;
+    assert_eq!(STRUCT.with_current_irqsave(|s| s.bar), 55);
+    STRUCT.with_current(|s| s.bar = 100);
+    U16.write_current(0xabcd);
+
+    // test scoped access to multiple variables
+    let sum = percpu::scope(|cpu| {
+        unsafe {
+            *U64.get_mut(cpu) += 1;
+            STRUCT.get_mut(cpu).bar += 1;
+        }
+        *U64.get(cpu) as usize + STRUCT.get(cpu).bar as usize
+    });
+    assert_eq!(sum, 0xa2ce_a2ce_a2ce_a2cf + 101);
+    percpu::scope_irqsave(|cpu| unsafe {
+        *U64.get_mut(cpu) -= 1;
+        STRUCT.get_mut(cpu).bar -= 1;
+    });
+    assert_eq!(U64.read_current(), 0xa2ce_a2ce_a2ce_a2ce);
+
+    // nested scopes can share references to the same per-CPU data
+    percpu::scope(|outer| {
+        let value = U64.get(outer);
+        percpu::scope(|inner| assert_eq!(U64.get(inner), value));
+        let cpu = CpuGuard::new();
+        assert_eq!(U64.get(&cpu), value);
+    });
+
+    // test safe access with a `CpuGuard`
+    {
+        let mut cpu = CpuGuard::new();
+        let val = U32.read(&cpu);
+        unsafe {
+            U32.write(&mut cpu, val + 1);
+            STRUCT.get_mut(&mut cpu).foo += 1;
+        }
+        assert_eq!(STRUCT.read(&cpu).foo, 0x2334);
+        unsafe { STRUCT.get_mut(&mut cpu).foo -= 1 };
+    }
+    {
+        let mut cpu = CpuGuard::new_irqsave();
+        assert_eq!(U32.read(&cpu), 0xdead_bef0);
+        unsafe { U32.write(&mut cpu, 0xdead_beef) };
+    }
+
+    // test access through the generic `PerCpu` trait
+    assert_eq!(generic_add(&QUALIFIED, 7), 50);
+    assert_eq!(PerCpu::read_current(&QUALIFIED), 50);
+    assert_eq!(generic_read_current(&STRUCT).foo, 0x2333);
+    QUALIFIED.write_current(43);
+
+    // the built-in CPU ID is 0 for "sp-naive" use
+    #[cfg(feature = "sp-naive")]
+    {
+        init_percpu_reg(0);
+        assert_eq!(this_cpu_id(), 0);
+        assert_eq!(cpu_id_of_area(base), Some(0));
+        assert_eq!(cpu_id_of_area(base + 1), None);
+    }
+
     #[cfg(not(feature = "sp-naive"))]
     test_remote_access();
 }
 
+fn struct_sum(s: &Struct) -> usize {
+    s.foo + s.bar as usize
+}
+
+fn generic_add<P: PerCpu<Target = u32>>(var: &P, n: u32) -> u32 {
+    var.with_current(|v| {
+        *v += n;
+        *v
+    })
+}
+
+fn generic_read_current<P: PerCpu>(var: &P) -> P::Target
+where
+    P::Target: Clone,
+{
+    var.read_current()
+}
+
+#[cfg(all(target_os = "linux", not(feature = "sp-naive")))]
+fn generic_sum<P: PerCpu>(var: &P) -> P::Target
+where
+    P::Target: Clone + core::iter::Sum,
+{
+    var.sum_remote()
+}
+
 #[cfg(all(target_os = "linux", not(feature = "sp-naive")))]
 fn test_remote_access() {
     // test remote write
@@ -136,6 +356,79 @@ fn test_remote_access() {
         assert_eq!(s.bar, 200);
     }
 
+    // test remote read/write of primitive types
+    unsafe {
+        BOOL.write_remote(2, true);
+        U8.write_remote(2, 111);
+        U16.write_remote(2, 0x5678);
+        U32.write_remote(2, 0xcafe_babe);
+        U64.write_remote(2, 0xdead_dead_dead_dead);
+        USIZE.write_remote(2, 0xabcd_ef00);
+    }
+
+    assert!(BOOL.read_remote(2));
+    assert_eq!(U8.read_remote(2), 111);
+    assert_eq!(U16.read_remote(2), 0x5678);
+    assert_eq!(U32.read_remote(2), 0xcafe_babe);
+    assert_eq!(U64.read_remote(2), 0xdead_dead_dead_dead);
+    assert_eq!(USIZE.read_remote(2), 0xabcd_ef00);
+    assert_eq!(U8.read_remote(1), 222);
+
+    assert!(std::panic::catch_unwind(|| U8.read_remote(percpu_area_num())).is_err());
+    assert_eq!(PerCpu::read_remote(&U32, 2), 0xcafe_babe);
+
+    // test bounds-checked remote accessors
+    assert_eq!(U32.try_read_remote(2), Some(0xcafe_babe));
+    assert_eq!(U32.try_read_remote(percpu_area_num()), None);
+    assert_eq!(STRUCT.try_read_remote(percpu_area_num()), None);
+    unsafe {
+        assert_eq!(U8.try_remote_ref(1), Some(&222));
+        assert!(U8.try_remote_ref(percpu_area_num()).is_none());
+        assert!(STRUCT.try_remote_mut(percpu_area_num()).is_none());
+        *U16.try_remote_mut(2).unwrap() += 1;
+    }
+    assert_eq!(U16.read_remote(2), 0x5679);
+    unsafe { U16.write_remote(2, 0x5678) };
+    #[cfg(feature = "debug-checks")]
+    assert!(std::panic::catch_unwind(|| unsafe { U8.remote_ptr(percpu_area_num()) }).is_err());
+
+    // test remote read/write of non-primitive types
+    unsafe {
+        STRUCT.write_remote(
+            2,
+            Struct {
+                foo: 0x7777,
+                bar: 77,
+            },
+        )
+    };
+    assert_eq!(
+        STRUCT.read_remote(2),
+        Struct {
+            foo: 0x7777,
+            bar: 77
+        }
+    );
+
+    // test iteration across all CPUs
+    for cpu_id in 0..percpu_area_num() {
+        unsafe { I32.write_remote(cpu_id, cpu_id as i32 - 1) };
+    }
+    let values: Vec<_> = unsafe { I32.iter_remote() }.map(|(i, v)| (i, *v)).collect();
+    assert_eq!(values.len(), percpu_area_num());
+    assert!(values.iter().all(|&(i, v)| v == i as i32 - 1));
+    let n = percpu_area_num() as i32;
+    assert_eq!(I32.sum_remote(), n * (n - 1) / 2 - n);
+    assert_eq!(I32.fold_remote(i32::MIN, i32::max), n - 2);
+    let bar_sum = unsafe { STRUCT.iter_remote() }
+        .map(|(_, s)| s.bar as usize)
+        .sum::<usize>();
+    assert_eq!(
+        STRUCT.fold_remote(0, |acc, s| acc + s.bar as usize),
+        bar_sum
+    );
+    assert_eq!(generic_sum(&I32), I32.sum_remote());
+
     // test read on another CPU
     unsafe { write_percpu_reg(percpu_area_base(1)) }; // we are now on CPU 1
 
@@ -154,6 +447,13 @@ fn test_remote_access() {
     assert_eq!(U64.read_current(), 0xfeed_feed_feed_feed);
     assert_eq!(USIZE.read_current(), 0x0000_ffff);
 
+    assert_eq!(
+        STRUCT.read_current(),
+        Struct {
+            foo: 0x6666,
+            bar: 200
+        }
+    );
     STRUCT.with_current(|s| {
         println!("struct.foo value on CPU 1: {:#x}", s.foo);
         println!("struct.bar value on CPU 1: {}", s.bar);
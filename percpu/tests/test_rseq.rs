@@ -0,0 +1,65 @@
+#![cfg(all(target_os = "linux", target_arch = "x86_64", feature = "rseq"))]
+#![cfg(not(feature = "loom"))]
+
+use std::sync::Barrier;
+
+use percpu::*;
+
+#[def_percpu]
+static COUNTER: u64 = 0;
+
+#[def_percpu]
+static FLAG: u8 = 0;
+
+#[def_percpu]
+static TOKEN: u16 = 0;
+
+#[def_percpu]
+static VALUE: u32 = 0;
+
+const THREADS: usize = 8;
+const ITERS: u64 = 100_000;
+
+#[test]
+fn test_rseq() {
+    // an area for every CPU configured in the system, regardless of `cpu_count`.
+    assert_eq!(init(1), percpu_area_num());
+    let cpu_num = std::thread::available_parallelism().unwrap().get();
+    assert!(percpu_area_num() >= cpu_num);
+    init_percpu_reg(0); // no effect
+
+    let cpu_id = this_cpu_id();
+    assert!(cpu_id < percpu_area_num());
+    assert_eq!(read_percpu_reg(), percpu_area_base(cpu_id));
+    assert_eq!(cpu_id_of_area(read_percpu_reg()), Some(cpu_id));
+
+    // the thread may be migrated between calls, so check the sums only.
+    assert_eq!(FLAG.cmpxchg_current(0, 1), Ok(0));
+    assert_eq!(FLAG.sum_remote(), 1);
+    TOKEN.add_current(0x100);
+    TOKEN.fetch_add_current(1);
+    assert_eq!(TOKEN.sum_remote(), 0x101);
+    VALUE.write_current(5);
+    assert_eq!(VALUE.sum_remote(), 5);
+    assert!(matches!(VALUE.read_current(), 0 | 5));
+
+    // increments from threads preempted in critical sections are not lost.
+    let barrier = Barrier::new(THREADS);
+    std::thread::scope(|s| {
+        for _ in 0..THREADS {
+            s.spawn(|| {
+                barrier.wait();
+                for _ in 0..ITERS {
+                    COUNTER.inc_current();
+                }
+                for _ in 0..ITERS {
+                    let mut old = 0;
+                    while let Err(cur) = COUNTER.cmpxchg_current(old, old + 1) {
+                        old = cur;
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(COUNTER.sum_remote(), 2 * THREADS as u64 * ITERS);
+}
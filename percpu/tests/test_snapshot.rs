@@ -0,0 +1,87 @@
+#![cfg(all(
+    target_os = "linux",
+    not(feature = "sp-naive"),
+    not(feature = "custom-tp")
+))]
+#![cfg(not(any(feature = "rseq", feature = "loom")))]
+
+use percpu::*;
+
+#[def_percpu]
+static VALUE: usize = 0;
+
+#[def_percpu]
+static NAME: [u8; 8] = [0; 8];
+
+#[test]
+fn test_snapshot() {
+    assert_eq!(init(4), 4);
+    init_percpu_reg(2);
+    assert_eq!(percpu_layout_hash(), percpu_layout_hash());
+    assert_eq!(snapshot_size(), SNAPSHOT_HEADER_SIZE + percpu_area_size());
+
+    unsafe { VALUE.write_remote(0, 42) };
+    unsafe { NAME.write_remote(0, *b"vcpu0\0\0\0") };
+    let mut buf = vec![0; snapshot_size()];
+    assert_eq!(unsafe { snapshot_area(0, &mut buf) }, Ok(snapshot_size()));
+    unsafe { VALUE.write_remote(0, 0) };
+
+    // the data is moved to the current CPU, which keeps its ID and register.
+    assert_eq!(cpu_offline(3), Ok(()));
+    assert_eq!(unsafe { restore_area(2, &buf) }, Ok(()));
+    assert_eq!(unsafe { restore_area(3, &buf) }, Ok(()));
+    assert_eq!(VALUE.read_current(), 42);
+    assert_eq!(NAME.read_current(), *b"vcpu0\0\0\0");
+    assert_eq!(this_cpu_id(), 2);
+    assert_eq!(read_percpu_reg(), percpu_area_base(2));
+    assert_eq!(VALUE.read_remote(3), 42);
+    assert!(!is_cpu_online(3));
+    assert_eq!(cpu_online(3, false), Ok(()));
+    assert_eq!(VALUE.read_remote(0), 0);
+
+    // invalid arguments
+    let mut small = vec![0; snapshot_size() - 1];
+    assert_eq!(
+        unsafe { snapshot_area(0, &mut small) },
+        Err(SnapshotError::BufferTooSmall {
+            required: snapshot_size(),
+            provided: snapshot_size() - 1,
+        })
+    );
+    assert_eq!(
+        unsafe { snapshot_area(4, &mut buf) },
+        Err(SnapshotError::InvalidCpuId)
+    );
+    assert_eq!(
+        unsafe { restore_area(4, &buf) },
+        Err(SnapshotError::InvalidCpuId)
+    );
+    assert_eq!(
+        unsafe { restore_area(1, &buf[..snapshot_size() - 1]) },
+        Err(SnapshotError::BufferTooSmall {
+            required: snapshot_size(),
+            provided: snapshot_size() - 1,
+        })
+    );
+    assert_eq!(
+        unsafe { restore_area(1, &buf[..SNAPSHOT_HEADER_SIZE - 1]) },
+        Err(SnapshotError::InvalidHeader)
+    );
+
+    // snapshots with a bad magic, or from a different build, are rejected.
+    let mut bad = buf.clone();
+    bad[0] ^= 1;
+    assert_eq!(
+        unsafe { restore_area(1, &bad) },
+        Err(SnapshotError::InvalidHeader)
+    );
+    for byte in [8, 16] {
+        let mut other_build = buf.clone();
+        other_build[byte] ^= 1;
+        assert_eq!(
+            unsafe { restore_area(1, &other_build) },
+            Err(SnapshotError::LayoutMismatch)
+        );
+    }
+    assert_eq!(VALUE.read_remote(1), 0);
+}
@@ -0,0 +1,62 @@
+#![cfg(all(
+    target_os = "linux",
+    feature = "testing",
+    not(feature = "sp-naive"),
+    not(feature = "custom-tp")
+))]
+#![cfg(not(feature = "loom"))]
+
+use std::panic::{self, AssertUnwindSafe};
+
+use percpu::testing::{Simulator, SimulatorError};
+use percpu::*;
+
+#[def_percpu]
+static VALUE: usize = 0;
+
+#[test]
+fn test_simulator() {
+    let sim = Simulator::new(4);
+    assert_eq!(sim.cpu_count(), 4);
+
+    let ids = sim.run_on_all(|cpu_id| {
+        VALUE.write_current(cpu_id * 10);
+        this_cpu_id()
+    });
+    assert_eq!(ids, [0, 1, 2, 3]);
+    for cpu_id in 0..4 {
+        assert_eq!(VALUE.read_remote(cpu_id), cpu_id * 10);
+    }
+    assert_eq!(sim.run_on(2, || VALUE.read_current()), 20);
+
+    // every CPU sees the values written by the others before the barrier.
+    let barrier = sim.barrier();
+    let neighbours = sim.run_on_all(move |cpu_id| {
+        VALUE.write_current(cpu_id + 100);
+        barrier.wait();
+        VALUE.read_remote((cpu_id + 1) % 4)
+    });
+    assert_eq!(neighbours, [101, 102, 103, 100]);
+
+    // panics are propagated, and the CPU keeps running.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| sim.run_on(1, || panic!("oops"))));
+    assert!(result.is_err());
+    assert_eq!(sim.run_on(1, this_cpu_id), 1);
+    drop(sim);
+
+    // the areas are initialized by the first simulator only.
+    assert_eq!(percpu_area_num(), 4);
+    assert_eq!(Simulator::new(2).cpu_count(), 2);
+    assert_eq!(
+        Simulator::try_new(5).err(),
+        Some(SimulatorError::TooFewAreas {
+            requested: 5,
+            available: 4
+        })
+    );
+    assert_eq!(
+        Simulator::try_new(0).err(),
+        Some(SimulatorError::InvalidCpuCount)
+    );
+    assert!(panic::catch_unwind(|| Simulator::new(5)).is_err());
+}
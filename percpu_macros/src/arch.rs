@@ -49,17 +49,40 @@ pub fn gen_offset(symbol: &Ident) -> proc_macro2::TokenStream {
     }
 }
 
-/// Generate a code block that calculates the pointer to the per-CPU variable on the current CPU, based on the inner
-/// symbol name and the type of the variable.
-pub fn gen_current_ptr(symbol: &Ident, ty: &Type) -> proc_macro2::TokenStream {
-    let aarch64_tpidr = if cfg!(feature = "arm-el2") {
+/// Returns the system register that holds the base address of the per-CPU data area on AArch64.
+fn aarch64_tpidr() -> &'static str {
+    if cfg!(feature = "arm-el2") {
         "TPIDR_EL2"
     } else {
         // For ARM architecture, we assume running in EL1 by default,
         // and use `TPIDR_EL1` to store the base address of the per-CPU data area.
         "TPIDR_EL1"
+    }
+}
+
+/// Returns the AArch64 instructions that load the address of the per-CPU variable on the current CPU into `{addr}`
+/// (clobbering `{tmp}`), the size suffix of the exclusive and LSE instructions, and the register modifier of the
+/// representation type.
+fn aarch64_atomic_prelude(ty_str: &str) -> (Vec<String>, &'static str, &'static str) {
+    let (suffix, modifier) = match ty_str {
+        "u8" => ("b", "w"),
+        "u16" => ("h", "w"),
+        "u32" => ("", "w"),
+        "u64" => ("", "x"),
+        _ => unreachable!(),
     };
-    let aarch64_asm = format!("mrs {{}}, {aarch64_tpidr}");
+    let prelude = vec![
+        format!("mrs {{addr}}, {}", aarch64_tpidr()),
+        "movz {tmp}, #:abs_g0_nc:{VAR}".into(), // Requires offset <= 0xffff
+        "add {addr}, {addr}, {tmp}".into(),
+    ];
+    (prelude, suffix, modifier)
+}
+
+/// Generate a code block that calculates the pointer to the per-CPU variable on the current CPU, based on the inner
+/// symbol name and the type of the variable.
+pub fn gen_current_ptr(symbol: &Ident, ty: &Type) -> proc_macro2::TokenStream {
+    let aarch64_asm = format!("mrs {{}}, {}", aarch64_tpidr());
 
     macos_unimplemented(quote! {
         let base: usize;
@@ -88,16 +111,16 @@ pub fn gen_current_ptr(symbol: &Ident, ty: &Type) -> proc_macro2::TokenStream {
 }
 
 /// Generate a code block that reads the value of the per-CPU variable on the current CPU, based on the inner symbol
-/// name and the type of the variable.
+/// name and the register representation type of the variable.
 ///
-/// The type of the variable must be one of the following: `bool`, `u8`, `u16`, `u32`, `u64`, or `usize`.
+/// The representation type must be one of the following: `u8`, `u16`, `u32`, or `u64`.
 pub fn gen_read_current_raw(symbol: &Ident, ty: &Type) -> proc_macro2::TokenStream {
     let ty_str = quote!(#ty).to_string();
     let rv64_op = match ty_str.as_str() {
-        "u8" | "bool" => "lbu",
+        "u8" => "lbu",
         "u16" => "lhu",
         "u32" => "lwu",
-        "u64" | "usize" => "ld",
+        "u64" => "ld",
         _ => unreachable!(),
     };
     let rv64_asm = quote! {
@@ -112,10 +135,10 @@ pub fn gen_read_current_raw(symbol: &Ident, ty: &Type) -> proc_macro2::TokenStre
 
     // https://loongson.github.io/LoongArch-Documentation/LoongArch-Vol1-EN.html#_ldx_buhuwud_stx_bhwd
     let la64_op = match ty_str.as_str() {
-        "u8" | "bool" => "ldx.bu",
+        "u8" => "ldx.bu",
         "u16" => "ldx.hu",
         "u32" => "ldx.wu",
-        "u64" | "usize" => "ldx.d",
+        "u64" => "ldx.d",
         _ => unreachable!(),
     };
     let la64_asm = quote! {
@@ -128,7 +151,7 @@ pub fn gen_read_current_raw(symbol: &Ident, ty: &Type) -> proc_macro2::TokenStre
         )
     };
 
-    let (x64_asm, x64_reg) = if ["bool", "u8"].contains(&ty_str.as_str()) {
+    let (x64_asm, x64_reg) = if ty_str == "u8" {
         (
             "mov {0}, byte ptr gs:[offset {VAR}]".into(),
             format_ident!("reg_byte"),
@@ -137,7 +160,7 @@ pub fn gen_read_current_raw(symbol: &Ident, ty: &Type) -> proc_macro2::TokenStre
         let (x64_mod, x64_ptr) = match ty_str.as_str() {
             "u16" => ("x", "word"),
             "u32" => ("e", "dword"),
-            "u64" | "usize" => ("r", "qword"),
+            "u64" => ("r", "qword"),
             _ => unreachable!(),
         };
         (
@@ -150,18 +173,10 @@ pub fn gen_read_current_raw(symbol: &Ident, ty: &Type) -> proc_macro2::TokenStre
     };
 
     let gen_code = |asm_stmt| {
-        if ty_str.as_str() == "bool" {
-            quote! {
-                let value: u8;
-                #asm_stmt;
-                value != 0
-            }
-        } else {
-            quote! {
-                let value: #ty;
-                #asm_stmt;
-                value
-            }
+        quote! {
+            let value: #ty;
+            #asm_stmt;
+            value
         }
     };
 
@@ -176,27 +191,22 @@ pub fn gen_read_current_raw(symbol: &Ident, ty: &Type) -> proc_macro2::TokenStre
         #[cfg(target_arch = "x86_64")]
         { #x64_code }
         #[cfg(not(any(target_arch = "riscv64", target_arch = "loongarch64", target_arch = "x86_64")))]
-        { *self.current_ptr() }
+        { *(self.current_ptr() as *const #ty) }
     })
 }
 
 /// Generate a code block that writes the value of the per-CPU variable on the current CPU, based on the inner symbol
-/// name, the identifier of the value to write, and the type of the variable.
+/// name, the identifier of the value to write, and the register representation type of the variable.
 ///
-/// The type of the variable must be one of the following: `bool`, `u8`, `u16`, `u32`, `u64`, or `usize`.
+/// The representation type must be one of the following: `u8`, `u16`, `u32`, or `u64`.
 pub fn gen_write_current_raw(symbol: &Ident, val: &Ident, ty: &Type) -> proc_macro2::TokenStream {
     let ty_str = quote!(#ty).to_string();
-    let ty_fixup = if ty_str.as_str() == "bool" {
-        format_ident!("u8")
-    } else {
-        format_ident!("{}", ty_str)
-    };
 
     let rv64_op = match ty_str.as_str() {
-        "u8" | "bool" => "sb",
+        "u8" => "sb",
         "u16" => "sh",
         "u32" => "sw",
-        "u64" | "usize" => "sd",
+        "u64" => "sd",
         _ => unreachable!(),
     };
     let rv64_code = quote! {
@@ -205,17 +215,17 @@ pub fn gen_write_current_raw(symbol: &Ident, val: &Ident, ty: &Type) -> proc_mac
             "add {0}, {0}, gp",
             concat!(#rv64_op, " {1}, %lo({VAR})({0})"),
             out(reg) _,
-            in(reg) #val as #ty_fixup,
+            in(reg) #val,
             VAR = sym #symbol,
         );
     };
 
     // https://loongson.github.io/LoongArch-Documentation/LoongArch-Vol1-EN.html#common-memory-access-instructions
     let la64_op = match ty_str.as_str() {
-        "u8" | "bool" => "stx.b",
+        "u8" => "stx.b",
         "u16" => "stx.h",
         "u32" => "stx.w",
-        "u64" | "usize" => "stx.d",
+        "u64" => "stx.d",
         _ => unreachable!(),
     };
     let la64_code = quote! {
@@ -224,12 +234,12 @@ pub fn gen_write_current_raw(symbol: &Ident, val: &Ident, ty: &Type) -> proc_mac
             "ori {0}, {0}, %abs_lo12({VAR})",
             concat!(#la64_op, " {1}, {0}, $r21"),
             out(reg) _,
-            in(reg) #val as #ty_fixup,
+            in(reg) #val,
             VAR = sym #symbol,
         );
     };
 
-    let (x64_asm, x64_reg) = if ["bool", "u8"].contains(&ty_str.as_str()) {
+    let (x64_asm, x64_reg) = if ty_str == "u8" {
         (
             "mov byte ptr gs:[offset {VAR}], {0}".into(),
             format_ident!("reg_byte"),
@@ -238,7 +248,7 @@ pub fn gen_write_current_raw(symbol: &Ident, val: &Ident, ty: &Type) -> proc_mac
         let (x64_mod, x64_ptr) = match ty_str.as_str() {
             "u16" => ("x", "word"),
             "u32" => ("e", "dword"),
-            "u64" | "usize" => ("r", "qword"),
+            "u64" => ("r", "qword"),
             _ => unreachable!(),
         };
         (
@@ -247,7 +257,7 @@ pub fn gen_write_current_raw(symbol: &Ident, val: &Ident, ty: &Type) -> proc_mac
         )
     };
     let x64_code = quote! {
-        ::core::arch::asm!(#x64_asm, in(#x64_reg) #val as #ty_fixup, VAR = sym #symbol)
+        ::core::arch::asm!(#x64_asm, in(#x64_reg) #val, VAR = sym #symbol)
     };
 
     macos_unimplemented(quote! {
@@ -261,3 +271,455 @@ pub fn gen_write_current_raw(symbol: &Ident, val: &Ident, ty: &Type) -> proc_mac
         { *(self.current_ptr() as *mut #ty) = #val }
     })
 }
+
+/// Generate a code block that adds the value to the per-CPU variable on the current CPU, based on the inner symbol
+/// name, the identifier of the value to add, and the type of the variable. If `fetch` is true, the code block
+/// evaluates to the previous value.
+///
+/// The addition is done by a single instruction on x86_64 (`add`/`xadd` with a `gs`-relative operand), by a single
+/// AMO instruction on riscv64 and loongarch64, and by `ldadd`/`stadd` (with LSE) or an exclusive load/store loop on
+/// aarch64, so it is safe against interrupts without disabling preemption. Narrow types on riscv64 and loongarch64,
+/// which have no such AMO instructions, and other architectures use an atomic operation on the current CPU's data.
+///
+/// The type of the variable must be one of the following: `u8`, `u16`, `u32`, or `u64`.
+pub fn gen_add_current(
+    symbol: &Ident,
+    val: &Ident,
+    ty: &Type,
+    fetch: bool,
+) -> proc_macro2::TokenStream {
+    let ty_str = quote!(#ty).to_string();
+    let fallback = crate::gen_atomic_fetch_add_current(val, ty);
+    let fallback = if fetch {
+        fallback
+    } else {
+        quote! { #fallback; }
+    };
+
+    let amo_suffix = match ty_str.as_str() {
+        "u32" => Some("w"),
+        "u64" => Some("d"),
+        _ => None,
+    };
+    let (rv64_code, la64_code) = if let Some(suffix) = amo_suffix {
+        let (rv64_op, la64_op) = (format!("amoadd.{suffix}"), format!("amadd.{suffix}"));
+        if fetch {
+            (
+                quote! {
+                    let value: #ty;
+                    ::core::arch::asm!(
+                        "lui {0}, %hi({VAR})",
+                        "add {0}, {0}, gp",
+                        "addi {0}, {0}, %lo({VAR})",
+                        concat!(#rv64_op, " {1}, {2}, ({0})"),
+                        out(reg) _,
+                        out(reg) value,
+                        in(reg) #val,
+                        VAR = sym #symbol,
+                    );
+                    value
+                },
+                quote! {
+                    let value: #ty;
+                    ::core::arch::asm!(
+                        "lu12i.w {0}, %abs_hi20({VAR})",
+                        "ori {0}, {0}, %abs_lo12({VAR})",
+                        "add.d {0}, {0}, $r21",
+                        concat!(#la64_op, " {1}, {2}, {0}"),
+                        out(reg) _,
+                        out(reg) value,
+                        in(reg) #val,
+                        VAR = sym #symbol,
+                    );
+                    value
+                },
+            )
+        } else {
+            (
+                quote! {
+                    ::core::arch::asm!(
+                        "lui {0}, %hi({VAR})",
+                        "add {0}, {0}, gp",
+                        "addi {0}, {0}, %lo({VAR})",
+                        concat!(#rv64_op, " zero, {1}, ({0})"),
+                        out(reg) _,
+                        in(reg) #val,
+                        VAR = sym #symbol,
+                    );
+                },
+                quote! {
+                    ::core::arch::asm!(
+                        "lu12i.w {0}, %abs_hi20({VAR})",
+                        "ori {0}, {0}, %abs_lo12({VAR})",
+                        "add.d {0}, {0}, $r21",
+                        concat!(#la64_op, " $zero, {1}, {0}"),
+                        out(reg) _,
+                        in(reg) #val,
+                        VAR = sym #symbol,
+                    );
+                },
+            )
+        }
+    } else {
+        (fallback.clone(), fallback.clone())
+    };
+
+    // `ldadd`/`stadd` with LSE, or an exclusive load/store loop otherwise.
+    let (a64_prelude, sz, m) = aarch64_atomic_prelude(&ty_str);
+    let a64_llsc = [
+        "1:".to_string(),
+        format!("ldxr{sz} {{prev:{m}}}, [{{addr}}]"),
+        format!("add {{tmp:{m}}}, {{prev:{m}}}, {{val:{m}}}"),
+        format!("stxr{sz} {{status:w}}, {{tmp:{m}}}, [{{addr}}]"),
+        "cbnz {status:w}, 1b".to_string(),
+    ];
+    let a64_code = if fetch {
+        let a64_lse = format!("ldadd{sz} {{val:{m}}}, {{prev:{m}}}, [{{addr}}]");
+        quote! {
+            let value: #ty;
+            #[cfg(target_feature = "lse")]
+            ::core::arch::asm!(
+                #(#a64_prelude,)* #a64_lse,
+                addr = out(reg) _, tmp = out(reg) _, val = in(reg) #val, prev = out(reg) value,
+                VAR = sym #symbol,
+            );
+            #[cfg(not(target_feature = "lse"))]
+            ::core::arch::asm!(
+                #(#a64_prelude,)* #(#a64_llsc,)*
+                addr = out(reg) _, tmp = out(reg) _, val = in(reg) #val, prev = out(reg) value,
+                status = out(reg) _, VAR = sym #symbol,
+            );
+            value
+        }
+    } else {
+        let a64_lse = format!("stadd{sz} {{val:{m}}}, [{{addr}}]");
+        quote! {
+            #[cfg(target_feature = "lse")]
+            ::core::arch::asm!(
+                #(#a64_prelude,)* #a64_lse,
+                addr = out(reg) _, tmp = out(reg) _, val = in(reg) #val,
+                VAR = sym #symbol,
+            );
+            #[cfg(not(target_feature = "lse"))]
+            ::core::arch::asm!(
+                #(#a64_prelude,)* #(#a64_llsc,)*
+                addr = out(reg) _, tmp = out(reg) _, val = in(reg) #val, prev = out(reg) _,
+                status = out(reg) _, VAR = sym #symbol,
+            );
+        }
+    };
+
+    let (x64_mod, x64_ptr, x64_reg) = match ty_str.as_str() {
+        "u8" => ("", "byte", format_ident!("reg_byte")),
+        "u16" => (":x", "word", format_ident!("reg")),
+        "u32" => (":e", "dword", format_ident!("reg")),
+        "u64" => (":r", "qword", format_ident!("reg")),
+        _ => unreachable!(),
+    };
+    let x64_code = if fetch {
+        let x64_asm = format!("xadd {x64_ptr} ptr gs:[offset {{VAR}}], {{0{x64_mod}}}");
+        quote! {
+            let value: #ty;
+            ::core::arch::asm!(#x64_asm, inout(#x64_reg) #val => value, VAR = sym #symbol);
+            value
+        }
+    } else {
+        let x64_asm = format!("add {x64_ptr} ptr gs:[offset {{VAR}}], {{0{x64_mod}}}");
+        quote! {
+            ::core::arch::asm!(#x64_asm, in(#x64_reg) #val, VAR = sym #symbol);
+        }
+    };
+
+    macos_unimplemented(quote! {
+        #[cfg(target_arch = "riscv64")]
+        { #rv64_code }
+        #[cfg(target_arch = "loongarch64")]
+        { #la64_code }
+        #[cfg(target_arch = "x86_64")]
+        { #x64_code }
+        #[cfg(target_arch = "aarch64")]
+        { #a64_code }
+        #[cfg(not(any(
+            target_arch = "riscv64",
+            target_arch = "loongarch64",
+            target_arch = "x86_64",
+            target_arch = "aarch64"
+        )))]
+        { #fallback }
+    })
+}
+
+/// Generate a code block that compares the per-CPU variable on the current CPU with `old` and replaces it with `new`
+/// if they are equal, based on the inner symbol name, the identifiers of the two values, and the type of the variable.
+/// The code block evaluates to `Ok(previous)` on success, or `Err(current)` on failure.
+///
+/// It is implemented by a single `cmpxchg` instruction on x86_64, an LL/SC loop on riscv64 and loongarch64, and `cas`
+/// (with LSE) or an exclusive load/store loop on aarch64, so it is safe against interrupts without disabling
+/// preemption. Narrow types on riscv64 and loongarch64, whose LL/SC instructions only operate on words and doublewords,
+/// and other architectures use an atomic operation on the current CPU's data.
+///
+/// The type of the variable must be one of the following: `u8`, `u16`, `u32`, or `u64`.
+pub fn gen_cmpxchg_current(
+    symbol: &Ident,
+    old: &Ident,
+    new: &Ident,
+    ty: &Type,
+) -> proc_macro2::TokenStream {
+    let ty_str = quote!(#ty).to_string();
+    let fallback = crate::gen_atomic_cmpxchg_current(old, new, ty);
+
+    let rv64_code = match ty_str.as_str() {
+        "u32" | "u64" => {
+            // `lr.w` sign-extends the loaded value, so does the value to compare with.
+            let (suffix, old_ext) = if ty_str == "u32" {
+                ("w", quote! { #old as i32 as i64 })
+            } else {
+                ("d", quote! { #old })
+            };
+            let (lr_op, sc_op) = (format!("lr.{suffix}"), format!("sc.{suffix}"));
+            quote! {
+                let prev: #ty;
+                ::core::arch::asm!(
+                    "lui {0}, %hi({VAR})",
+                    "add {0}, {0}, gp",
+                    "addi {0}, {0}, %lo({VAR})",
+                    "1:",
+                    concat!(#lr_op, " {1}, ({0})"),
+                    "bne {1}, {2}, 2f",
+                    concat!(#sc_op, " {4}, {3}, ({0})"),
+                    "bnez {4}, 1b",
+                    "2:",
+                    out(reg) _,
+                    out(reg) prev,
+                    in(reg) #old_ext,
+                    in(reg) #new,
+                    out(reg) _,
+                    VAR = sym #symbol,
+                );
+                if prev == #old { Ok(prev) } else { Err(prev) }
+            }
+        }
+        _ => fallback.clone(),
+    };
+
+    let la64_code = match ty_str.as_str() {
+        "u32" | "u64" => {
+            // `ll.w` sign-extends the loaded value, so does the value to compare with.
+            let (suffix, old_ext) = if ty_str == "u32" {
+                ("w", quote! { #old as i32 as i64 })
+            } else {
+                ("d", quote! { #old })
+            };
+            let (ll_op, sc_op) = (format!("ll.{suffix}"), format!("sc.{suffix}"));
+            quote! {
+                let prev: #ty;
+                ::core::arch::asm!(
+                    "lu12i.w {0}, %abs_hi20({VAR})",
+                    "ori {0}, {0}, %abs_lo12({VAR})",
+                    "add.d {0}, {0}, $r21",
+                    "1:",
+                    concat!(#ll_op, " {1}, {0}, 0"),
+                    "bne {1}, {2}, 2f",
+                    "move {4}, {3}",
+                    concat!(#sc_op, " {4}, {0}, 0"),
+                    "beqz {4}, 1b",
+                    "b 3f",
+                    "2:",
+                    "dbar 0x700",
+                    "3:",
+                    out(reg) _,
+                    out(reg) prev,
+                    in(reg) #old_ext,
+                    in(reg) #new,
+                    out(reg) _,
+                    VAR = sym #symbol,
+                );
+                if prev == #old { Ok(prev) } else { Err(prev) }
+            }
+        }
+        _ => fallback.clone(),
+    };
+
+    let (x64_mod, x64_ptr, x64_reg, x64_acc) = match ty_str.as_str() {
+        "u8" => ("", "byte", format_ident!("reg_byte"), "al"),
+        "u16" => (":x", "word", format_ident!("reg"), "ax"),
+        "u32" => (":e", "dword", format_ident!("reg"), "eax"),
+        "u64" => (":r", "qword", format_ident!("reg"), "rax"),
+        _ => unreachable!(),
+    };
+    let x64_asm = format!("cmpxchg {x64_ptr} ptr gs:[offset {{VAR}}], {{0{x64_mod}}}");
+    let x64_code = quote! {
+        let prev: #ty;
+        ::core::arch::asm!(#x64_asm, in(#x64_reg) #new, inout(#x64_acc) #old => prev, VAR = sym #symbol);
+        if prev == #old { Ok(prev) } else { Err(prev) }
+    };
+
+    // `cas` with LSE, or an exclusive load/store loop otherwise. The upper bits of the register holding a narrow `old`
+    // are undefined, so the comparison extends it from the low bits.
+    let (a64_prelude, sz, m) = aarch64_atomic_prelude(&ty_str);
+    let a64_lse = format!("cas{sz} {{prev:{m}}}, {{new:{m}}}, [{{addr}}]");
+    let a64_cmp = match ty_str.as_str() {
+        "u8" => "cmp {prev:w}, {old:w}, uxtb".to_string(),
+        "u16" => "cmp {prev:w}, {old:w}, uxth".to_string(),
+        _ => format!("cmp {{prev:{m}}}, {{old:{m}}}"),
+    };
+    let a64_llsc = [
+        "1:".to_string(),
+        format!("ldxr{sz} {{prev:{m}}}, [{{addr}}]"),
+        a64_cmp,
+        "b.ne 2f".to_string(),
+        format!("stxr{sz} {{status:w}}, {{new:{m}}}, [{{addr}}]"),
+        "cbnz {status:w}, 1b".to_string(),
+        "b 3f".to_string(),
+        "2:".to_string(),
+        "clrex".to_string(),
+        "3:".to_string(),
+    ];
+    let a64_code = quote! {
+        let prev: #ty;
+        #[cfg(target_feature = "lse")]
+        ::core::arch::asm!(
+            #(#a64_prelude,)* #a64_lse,
+            addr = out(reg) _, tmp = out(reg) _, new = in(reg) #new, prev = inout(reg) #old => prev,
+            VAR = sym #symbol,
+        );
+        #[cfg(not(target_feature = "lse"))]
+        ::core::arch::asm!(
+            #(#a64_prelude,)* #(#a64_llsc,)*
+            addr = out(reg) _, tmp = out(reg) _, old = in(reg) #old, new = in(reg) #new, prev = out(reg) prev,
+            status = out(reg) _, VAR = sym #symbol,
+        );
+        if prev == #old { Ok(prev) } else { Err(prev) }
+    };
+
+    macos_unimplemented(quote! {
+        #[cfg(target_arch = "riscv64")]
+        { #rv64_code }
+        #[cfg(target_arch = "loongarch64")]
+        { #la64_code }
+        #[cfg(target_arch = "x86_64")]
+        { #x64_code }
+        #[cfg(target_arch = "aarch64")]
+        { #a64_code }
+        #[cfg(not(any(
+            target_arch = "riscv64",
+            target_arch = "loongarch64",
+            target_arch = "x86_64",
+            target_arch = "aarch64"
+        )))]
+        { #fallback }
+    })
+}
+
+/// Generate a code block that stores `new` to the per-CPU variable on the current CPU and evaluates to the previous
+/// value, based on the inner symbol name, the identifier of the value to store, and the type of the variable.
+///
+/// It is implemented by a single `xchg` instruction on x86_64, a single AMO instruction on riscv64 and loongarch64, and
+/// `swp` (with LSE) or an exclusive load/store loop on aarch64, so it is safe against interrupts without disabling
+/// preemption. Narrow types on riscv64 and loongarch64, which have no such AMO instructions, and other architectures
+/// use an atomic operation on the current CPU's data.
+///
+/// The type of the variable must be one of the following: `u8`, `u16`, `u32`, or `u64`.
+pub fn gen_xchg_current(symbol: &Ident, new: &Ident, ty: &Type) -> proc_macro2::TokenStream {
+    let ty_str = quote!(#ty).to_string();
+    let fallback = crate::gen_atomic_xchg_current(new, ty);
+
+    let amo_suffix = match ty_str.as_str() {
+        "u32" => Some("w"),
+        "u64" => Some("d"),
+        _ => None,
+    };
+    let (rv64_code, la64_code) = if let Some(suffix) = amo_suffix {
+        let (rv64_op, la64_op) = (format!("amoswap.{suffix}"), format!("amswap.{suffix}"));
+        (
+            quote! {
+                let prev: #ty;
+                ::core::arch::asm!(
+                    "lui {0}, %hi({VAR})",
+                    "add {0}, {0}, gp",
+                    "addi {0}, {0}, %lo({VAR})",
+                    concat!(#rv64_op, " {1}, {2}, ({0})"),
+                    out(reg) _,
+                    out(reg) prev,
+                    in(reg) #new,
+                    VAR = sym #symbol,
+                );
+                prev
+            },
+            quote! {
+                let prev: #ty;
+                ::core::arch::asm!(
+                    "lu12i.w {0}, %abs_hi20({VAR})",
+                    "ori {0}, {0}, %abs_lo12({VAR})",
+                    "add.d {0}, {0}, $r21",
+                    concat!(#la64_op, " {1}, {2}, {0}"),
+                    out(reg) _,
+                    out(reg) prev,
+                    in(reg) #new,
+                    VAR = sym #symbol,
+                );
+                prev
+            },
+        )
+    } else {
+        (fallback.clone(), fallback.clone())
+    };
+
+    let (x64_mod, x64_ptr, x64_reg) = match ty_str.as_str() {
+        "u8" => ("", "byte", format_ident!("reg_byte")),
+        "u16" => (":x", "word", format_ident!("reg")),
+        "u32" => (":e", "dword", format_ident!("reg")),
+        "u64" => (":r", "qword", format_ident!("reg")),
+        _ => unreachable!(),
+    };
+    let x64_asm = format!("xchg {x64_ptr} ptr gs:[offset {{VAR}}], {{0{x64_mod}}}");
+
+    // `swp` with LSE, or an exclusive load/store loop otherwise.
+    let (a64_prelude, sz, m) = aarch64_atomic_prelude(&ty_str);
+    let a64_lse = format!("swp{sz} {{new:{m}}}, {{prev:{m}}}, [{{addr}}]");
+    let a64_llsc = [
+        "1:".to_string(),
+        format!("ldxr{sz} {{prev:{m}}}, [{{addr}}]"),
+        format!("stxr{sz} {{status:w}}, {{new:{m}}}, [{{addr}}]"),
+        "cbnz {status:w}, 1b".to_string(),
+    ];
+    let a64_code = quote! {
+        let prev: #ty;
+        #[cfg(target_feature = "lse")]
+        ::core::arch::asm!(
+            #(#a64_prelude,)* #a64_lse,
+            addr = out(reg) _, tmp = out(reg) _, new = in(reg) #new, prev = out(reg) prev,
+            VAR = sym #symbol,
+        );
+        #[cfg(not(target_feature = "lse"))]
+        ::core::arch::asm!(
+            #(#a64_prelude,)* #(#a64_llsc,)*
+            addr = out(reg) _, tmp = out(reg) _, new = in(reg) #new, prev = out(reg) prev,
+            status = out(reg) _, VAR = sym #symbol,
+        );
+        prev
+    };
+    let x64_code = quote! {
+        let prev: #ty;
+        ::core::arch::asm!(#x64_asm, inout(#x64_reg) #new => prev, VAR = sym #symbol);
+        prev
+    };
+
+    macos_unimplemented(quote! {
+        #[cfg(target_arch = "riscv64")]
+        { #rv64_code }
+        #[cfg(target_arch = "loongarch64")]
+        { #la64_code }
+        #[cfg(target_arch = "x86_64")]
+        { #x64_code }
+        #[cfg(target_arch = "aarch64")]
+        { #a64_code }
+        #[cfg(not(any(
+            target_arch = "riscv64",
+            target_arch = "loongarch64",
+            target_arch = "x86_64",
+            target_arch = "aarch64"
+        )))]
+        { #fallback }
+    })
+}
@@ -43,19 +43,33 @@
 //!
 //! - A zero-sized wrapper struct `X_WRAPPER` that is used to access the per-CPU data.
 //!
-//!   Some methods are generated in this struct to access the per-CPU data. For primitive integer types, extra methods
-//!   are generated to accelerate the access.
+//!   Some methods are generated in this struct to access the per-CPU data. Methods that need more than a pointer are
+//!   generated with a `where` clause (e.g., on `Clone` or `percpu::PerCpuInteger`), so they are only callable for the
+//!   types that satisfy it. For primitive types (`bool`, integers, thin raw pointers, `NonNull<T>` and
+//!   `Option<NonNull<T>>`), the value accessors are accelerated to single instructions.
+//!
+//!   The `percpu::PerCpu` trait is also implemented for this struct, so that it can be used in generic code.
 //!
 //! - A static variable `X` of type `X_WRAPPER` that is used to access the per-CPU data.
 //!
 //!   This variable is always generated with the same visibility and attributes as the original static variable.
 //!
+//! - A static variable `__PERCPU_LAYOUT_X` in the `percpu_layout` section, which records the name, type, size and
+//!   offset of the per-CPU data, so that snapshots of per-CPU data areas can be checked against the layout of the
+//!   program (except for "sp-naive" use, macOS and Windows).
+//!
 
 use proc_macro::TokenStream;
 use quote::quote;
 use syn::{Error, ItemStatic};
 
+#[cfg(any(feature = "sp-naive", not(feature = "custom-tp")))]
 #[cfg_attr(feature = "sp-naive", path = "naive.rs")]
+#[cfg_attr(all(feature = "rseq", not(feature = "sp-naive")), path = "rseq.rs")]
+#[cfg_attr(
+    all(feature = "loom", not(any(feature = "sp-naive", feature = "rseq"))),
+    path = "loom.rs"
+)]
 mod arch;
 
 #[allow(unused)]
@@ -63,10 +77,41 @@ fn compiler_error(err: Error) -> TokenStream {
     err.to_compile_error().into()
 }
 
+/// Arguments of the `def_percpu` attribute.
+#[derive(Default)]
+struct PerCpuArgs {
+    /// Whether the default accessors disable local IRQs as well as preemption.
+    irq_safe: bool,
+}
+
+impl syn::parse::Parse for PerCpuArgs {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let mut args = PerCpuArgs::default();
+        let idents =
+            syn::punctuated::Punctuated::<syn::Ident, syn::Token![,]>::parse_terminated(input)?;
+        for ident in idents {
+            match ident.to_string().as_str() {
+                "irq_safe" => args.irq_safe = true,
+                _ => {
+                    return Err(Error::new(
+                        ident.span(),
+                        "unknown argument, expect `#[def_percpu]` or `#[def_percpu(irq_safe)]`",
+                    ))
+                }
+            }
+        }
+        Ok(args)
+    }
+}
+
 /// Defines a per-CPU static variable.
 ///
 /// It should be used on a `static` variable definition.
 ///
+/// With `#[def_percpu(irq_safe)]`, the default accessors (e.g., `with_current`, `read_current`, `current`) disable
+/// local IRQs as well as preemption, like their `*_irqsave` variants. Use it for per-CPU data that is also accessed
+/// from interrupt handlers.
+///
 /// See the documentation of the [percpu](https://docs.rs/percpu) crate for more details.
 #[proc_macro_attribute]
 pub fn def_percpu(attr: TokenStream, item: TokenStream) -> TokenStream {
@@ -82,18 +127,227 @@ pub fn percpu_symbol_offset(item: TokenStream) -> TokenStream {
     quote!({ #offset }).into()
 }
 
+/// Generate a statement that disables preemption until the end of the enclosing block, if the `preempt` feature is
+/// enabled.
 #[cfg(any(feature = "sp-naive", not(feature = "custom-tp")))]
-fn def_percpu_impl(attr: TokenStream, item: TokenStream) -> TokenStream {
-    use proc_macro2::Span;
-    use quote::format_ident;
+fn gen_no_preempt_guard() -> proc_macro2::TokenStream {
+    if cfg!(feature = "preempt") {
+        quote! { let _guard = percpu::__priv::NoPreemptGuard::new(); }
+    } else {
+        quote! {}
+    }
+}
 
-    if !attr.is_empty() {
-        return compiler_error(Error::new(
-            Span::call_site(),
-            "expect an empty attribute: `#[def_percpu]`",
-        ));
+/// Generate a statement that records an access to the per-CPU data at `ptr` for loom to check, if the `loom` feature
+/// is enabled.
+#[cfg(any(feature = "sp-naive", not(feature = "custom-tp")))]
+fn gen_loom_access(ptr: proc_macro2::TokenStream, write: bool) -> proc_macro2::TokenStream {
+    if !cfg!(feature = "loom") {
+        quote! {}
+    } else if write {
+        quote! { percpu::__priv::track_write(#ptr as usize); }
+    } else {
+        quote! { percpu::__priv::track_read(#ptr as usize); }
+    }
+}
+
+/// Generate a static variable that describes the layout of the per-CPU variable `name` in the `percpu_layout`
+/// section, from which `percpu::percpu_layout_hash()` is computed. Nothing is generated for "sp-naive" use, or on
+/// targets without `__start_`/`__stop_` section symbols.
+///
+/// `data` is the static variable in the `.percpu` section, whose address is the offset of the variable plus
+/// `_percpu_load_start`. It is not recorded as an offset, because the `offset()` of the default backend needs absolute
+/// relocations, which fail to link position-independent executables that do not access per-CPU data at all.
+///
+/// `attrs` are the attributes of the variable, of which only `cfg`s are kept.
+fn gen_layout_entry(
+    name: &syn::Ident,
+    data: &syn::Ident,
+    ty: &syn::Type,
+    attrs: &[syn::Attribute],
+) -> proc_macro2::TokenStream {
+    if cfg!(feature = "sp-naive") {
+        return quote! {};
+    }
+    // FNV-1a, identifies the variable by its name and type.
+    let id = format!("{}: {}", name, quote! { #ty })
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3)
+        });
+    let entry_name = quote::format_ident!("__PERCPU_LAYOUT_{}", name);
+    let cfgs = attrs.iter().filter(|attr| attr.path().is_ident("cfg"));
+    // the same forms as the `.percpu` attribute and paths of each backend.
+    let (link_section, krate) = if cfg!(feature = "custom-tp") {
+        (
+            quote! { #[unsafe(link_section = "percpu_layout")] },
+            quote! { ::percpu },
+        )
+    } else {
+        (
+            quote! { #[link_section = "percpu_layout"] },
+            quote! { percpu },
+        )
+    };
+    quote! {
+        #(#cfgs)*
+        #[cfg(not(any(target_os = "macos", windows)))]
+        #link_section
+        #[used]
+        #[allow(non_upper_case_globals, unused_unsafe)]
+        static #entry_name: #krate::__priv::LayoutEntry = #krate::__priv::LayoutEntry {
+            id: #id,
+            size: ::core::mem::size_of::<#ty>(),
+            align: ::core::mem::align_of::<#ty>(),
+            type_name: ::core::any::type_name::<#ty>,
+            data: unsafe { ::core::ptr::addr_of!(#data) } as *const (),
+        };
+    }
+}
+
+/// Generate a statement that disables local IRQs, as well as preemption if the `preempt` feature is enabled, until
+/// the end of the enclosing block.
+#[cfg(any(feature = "sp-naive", not(feature = "custom-tp")))]
+fn gen_irq_save_guard() -> proc_macro2::TokenStream {
+    quote! { let _guard = percpu::__priv::IrqSaveGuard::new(); }
+}
+
+/// Generate a statement that asserts `cpu_id` is less than `percpu_area_num()`, if the `debug-checks` feature is
+/// enabled.
+#[cfg(any(feature = "sp-naive", not(feature = "custom-tp")))]
+fn gen_cpu_id_check() -> proc_macro2::TokenStream {
+    if cfg!(feature = "debug-checks") {
+        quote! { assert!(cpu_id < percpu::percpu_area_num(), "invalid CPU ID: {}", cpu_id); }
+    } else {
+        quote! {}
+    }
+}
+
+/// Generate a code block that operates on the per-CPU variable on the current CPU through an atomic view of
+/// `self.current_ptr()`, used on architectures without a dedicated instruction sequence.
+///
+/// `atomic_op` is evaluated with `atomic` bound to the atomic view. Targets without atomics of the required width
+/// evaluate `plain_op` instead, with `ptr` bound to the `*mut T` pointer and local IRQs disabled.
+///
+/// The type of the variable must be one of the following: `u8`, `u16`, `u32`, or `u64`.
+#[cfg(any(feature = "sp-naive", not(feature = "custom-tp")))]
+fn gen_atomic_current(
+    ty: &syn::Type,
+    atomic_op: proc_macro2::TokenStream,
+    plain_op: proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    let ty_str = quote!(#ty).to_string();
+    let (atomic_ty, width) = match ty_str.as_str() {
+        "u8" => ("AtomicU8", "8"),
+        "u16" => ("AtomicU16", "16"),
+        "u32" => ("AtomicU32", "32"),
+        "u64" => ("AtomicU64", "64"),
+        _ => unreachable!(),
+    };
+    let atomic_ty = quote::format_ident!("{}", atomic_ty);
+    let irq_save_guard = gen_irq_save_guard();
+    quote! {
+        {
+            #[cfg(target_has_atomic = #width)]
+            {
+                let atomic = &*(self.current_ptr() as *const ::core::sync::atomic::#atomic_ty);
+                #atomic_op
+            }
+            #[cfg(not(target_has_atomic = #width))]
+            {
+                #irq_save_guard
+                let ptr = self.current_ptr() as *mut #ty;
+                #plain_op
+            }
+        }
+    }
+}
+
+/// Generate a code block that adds the value to the per-CPU variable on the current CPU with [`gen_atomic_current`],
+/// and evaluates to the previous value.
+#[cfg(any(feature = "sp-naive", not(feature = "custom-tp")))]
+fn gen_atomic_fetch_add_current(val: &syn::Ident, ty: &syn::Type) -> proc_macro2::TokenStream {
+    gen_atomic_current(
+        ty,
+        quote! { atomic.fetch_add(#val, ::core::sync::atomic::Ordering::Relaxed) },
+        quote! {
+            let old = *ptr;
+            *ptr = old.wrapping_add(#val);
+            old
+        },
+    )
+}
+
+/// Generate a code block that compares the per-CPU variable on the current CPU with `old` and replaces it with `new`
+/// if they are equal, with [`gen_atomic_current`]. The code block evaluates to `Result<T, T>` like
+/// `AtomicT::compare_exchange`.
+#[cfg(any(feature = "sp-naive", not(feature = "custom-tp")))]
+fn gen_atomic_cmpxchg_current(
+    old: &syn::Ident,
+    new: &syn::Ident,
+    ty: &syn::Type,
+) -> proc_macro2::TokenStream {
+    gen_atomic_current(
+        ty,
+        quote! {
+            atomic.compare_exchange(
+                #old,
+                #new,
+                ::core::sync::atomic::Ordering::Relaxed,
+                ::core::sync::atomic::Ordering::Relaxed,
+            )
+        },
+        quote! {
+            let prev = *ptr;
+            if prev == #old {
+                *ptr = #new;
+                Ok(prev)
+            } else {
+                Err(prev)
+            }
+        },
+    )
+}
+
+/// Generate a code block that stores `new` to the per-CPU variable on the current CPU with [`gen_atomic_current`],
+/// and evaluates to the previous value.
+#[cfg(any(feature = "sp-naive", not(feature = "custom-tp")))]
+fn gen_atomic_xchg_current(new: &syn::Ident, ty: &syn::Type) -> proc_macro2::TokenStream {
+    gen_atomic_current(
+        ty,
+        quote! { atomic.swap(#new, ::core::sync::atomic::Ordering::Relaxed) },
+        quote! { ::core::mem::replace(&mut *ptr, #new) },
+    )
+}
+
+/// Generate a `match` expression on the size of the per-CPU variable type, whose arms are generated by `gen_arm` with
+/// the unsigned integer type of the same size (`u8`, `u16`, `u32`, or `u64`).
+///
+/// The size is a constant, so only one of the arms survives after compilation.
+#[cfg(any(feature = "sp-naive", not(feature = "custom-tp")))]
+fn gen_match_size(
+    ty: &syn::Type,
+    gen_arm: impl Fn(&syn::Type) -> proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    let arm_u8 = gen_arm(&syn::parse_quote!(u8));
+    let arm_u16 = gen_arm(&syn::parse_quote!(u16));
+    let arm_u32 = gen_arm(&syn::parse_quote!(u32));
+    let arm_u64 = gen_arm(&syn::parse_quote!(u64));
+    quote! {
+        match ::core::mem::size_of::<#ty>() {
+            1 => { #arm_u8 }
+            2 => { #arm_u16 }
+            4 => { #arm_u32 }
+            _ => { #arm_u64 }
+        }
     }
+}
+
+#[cfg(any(feature = "sp-naive", not(feature = "custom-tp")))]
+fn def_percpu_impl(attr: TokenStream, item: TokenStream) -> TokenStream {
+    use quote::format_ident;
 
+    let args = syn::parse_macro_input!(attr as PerCpuArgs);
     let ast = syn::parse_macro_input!(item as ItemStatic);
 
     let attrs = &ast.attrs;
@@ -105,69 +359,454 @@ fn def_percpu_impl(attr: TokenStream, item: TokenStream) -> TokenStream {
     let inner_symbol_name = &format_ident!("__PERCPU_{}", name);
     let struct_name = &format_ident!("{}_WRAPPER", name);
 
-    let ty_str = quote!(#ty).to_string();
-    let is_primitive_int = ["bool", "u8", "u16", "u32", "u64", "usize"].contains(&ty_str.as_str());
-
-    let no_preempt_guard = if cfg!(feature = "preempt") {
-        quote! { let _guard = percpu::__priv::NoPreemptGuard::new(); }
+    // The guard of the default accessors, and the guard type of `current()` and `current_mut()`.
+    let (default_guard, default_guard_ty) = if args.irq_safe {
+        (
+            gen_irq_save_guard(),
+            quote! { percpu::__priv::IrqSaveGuard },
+        )
     } else {
-        quote! {}
+        (
+            gen_no_preempt_guard(),
+            quote! { percpu::__priv::NoPreemptGuard },
+        )
     };
+    let irq_save_guard = gen_irq_save_guard();
+    let cpu_id_check = gen_cpu_id_check();
+    let disabled = if args.irq_safe {
+        "Local IRQs and preemption"
+    } else {
+        "Preemption"
+    };
+    let during_call_doc = format!(" {} will be disabled during the call.", disabled);
+    let until_drop_doc = format!(" {} will be disabled until the guard is dropped.", disabled);
+
+    // Some methods are only callable if the type implements `Clone`, `Sum` or `PerCpuInteger`, which is resolved by the
+    // compiler so that the type can be spelled in any way. The higher-ranked bound makes the `where` clause valid even
+    // if it does not hold.
+    let clone_bound = quote! { where for<'a> #ty: Clone };
+    let sum_bound = quote! { where for<'a> #ty: Clone + core::iter::Sum };
+    let integer_bound = quote! { where for<'a> #ty: percpu::PerCpuInteger };
+
+    let read_current_raw = gen_match_size(ty, |repr| {
+        let read = arch::gen_read_current_raw(inner_symbol_name, repr);
+        quote! {
+            let value: #repr = #read;
+            value as u64
+        }
+    });
+    let write_current_raw = gen_match_size(ty, |repr| {
+        let write = arch::gen_write_current_raw(inner_symbol_name, &format_ident!("val"), repr);
+        quote! {
+            let val = bits as #repr;
+            #write
+        }
+    });
+    // `read_current_raw()` and `write_current_raw()` take the fast path for `PerCpuPrimitive` types, and fall back to
+    // `Clone` and assignment through `current_ptr()` for other types. See `percpu::__priv::Dispatch`.
+    let read_write_methods = quote! {
+        /// Returns the value of the per-CPU static variable on the current CPU.
+        ///
+        /// For primitive types, the value is read by a single instruction if possible. Otherwise it is cloned.
+        ///
+        /// # Safety
+        ///
+        /// Caller must ensure that preemption is disabled on the current CPU.
+        #[inline]
+        pub unsafe fn read_current_raw(&self) -> #ty #clone_bound {
+            use percpu::__priv::{DispatchOther as _, DispatchPrimitive as _};
+            (&percpu::__priv::Dispatch::<#ty>::new()).read(
+                || #read_current_raw,
+                || self.current_ref_raw().clone(),
+            )
+        }
+
+        /// Set the value of the per-CPU static variable on the current CPU.
+        ///
+        /// For primitive types, the value is written by a single instruction if possible. Otherwise the old value is
+        /// dropped and replaced.
+        ///
+        /// # Safety
+        ///
+        /// Caller must ensure that preemption is disabled on the current CPU.
+        #[inline]
+        pub unsafe fn write_current_raw(&self, val: #ty) {
+            use percpu::__priv::{DispatchOther as _, DispatchPrimitive as _};
+            (&percpu::__priv::Dispatch::<#ty>::new()).write(
+                val,
+                |bits| #write_current_raw,
+                |val| *self.current_ref_mut_raw() = val,
+            )
+        }
+
+        /// Returns the value of the per-CPU static variable on the given CPU.
+        ///
+        /// # Safety
+        ///
+        /// Caller must ensure that
+        /// - the CPU ID is valid, and
+        /// - data races will not happen.
+        #[inline]
+        pub unsafe fn read_remote_raw(&self, cpu_id: usize) -> #ty #clone_bound {
+            self.remote_ref_raw(cpu_id).clone()
+        }
+
+        /// Set the value of the per-CPU static variable on the given CPU.
+        ///
+        /// # Safety
+        ///
+        /// Caller must ensure that
+        /// - the CPU ID is valid, and
+        /// - data races will not happen.
+        #[inline]
+        pub unsafe fn write_remote_raw(&self, cpu_id: usize, val: #ty) {
+            *self.remote_ref_mut_raw(cpu_id) = val
+        }
+
+        /// Returns the value of the per-CPU static variable on the given CPU.
+        #[doc = #during_call_doc]
+        ///
+        /// # Panics
+        ///
+        /// Panics if `cpu_id` is not less than `percpu_area_num()`.
+        pub fn read_remote(&self, cpu_id: usize) -> #ty #clone_bound {
+            assert!(cpu_id < percpu::percpu_area_num(), "invalid CPU ID: {}", cpu_id);
+            #default_guard
+            unsafe { self.read_remote_raw(cpu_id) }
+        }
+
+        /// Returns the value of the per-CPU static variable on the given CPU, or `None` if `cpu_id` is not less
+        /// than `percpu_area_num()`.
+        #[doc = #during_call_doc]
+        pub fn try_read_remote(&self, cpu_id: usize) -> Option<#ty> #clone_bound {
+            (cpu_id < percpu::percpu_area_num()).then(|| self.read_remote(cpu_id))
+        }
+
+        /// Set the value of the per-CPU static variable on the given CPU.
+        #[doc = #during_call_doc]
+        ///
+        /// # Safety
+        ///
+        /// Caller must ensure that data races will not happen, i.e., the per-CPU data on that CPU is not accessed
+        /// concurrently (for example, the CPU is not online yet, or all accesses are serialized by a lock).
+        ///
+        /// # Panics
+        ///
+        /// Panics if `cpu_id` is not less than `percpu_area_num()`.
+        pub unsafe fn write_remote(&self, cpu_id: usize, val: #ty) {
+            assert!(cpu_id < percpu::percpu_area_num(), "invalid CPU ID: {}", cpu_id);
+            #default_guard
+            self.write_remote_raw(cpu_id, val)
+        }
+
+        /// Restores the per-CPU static variable on the given CPU to its initial value in the link-time template,
+        /// dropping the old value.
+        #[doc = #during_call_doc]
+        ///
+        /// Returns an error if `cpu_id` is not less than `percpu_area_num()`, or the template is unavailable.
+        ///
+        /// # Safety
+        ///
+        /// The same as [`write_remote`](Self::write_remote).
+        pub unsafe fn reset_remote(&self, cpu_id: usize) -> Result<(), percpu::ResetError> {
+            <Self as percpu::PerCpu>::reset_remote(self, cpu_id)
+        }
+
+        /// Folds the values of the per-CPU static variable on all CPUs into an accumulator, reading each of them
+        /// with `read_remote()`.
+        ///
+        /// The values are read one by one, so the result is not an atomic snapshot if they are updated concurrently.
+        pub fn fold_remote<B, F>(&self, init: B, f: F) -> B
+        where
+            for<'a> #ty: Clone,
+            F: FnMut(B, #ty) -> B,
+        {
+            <Self as percpu::PerCpu>::fold_remote(self, init, f)
+        }
+
+        /// Returns the sum of the per-CPU static variable on all CPUs.
+        ///
+        /// Like `fold_remote()`, it is not an atomic snapshot if the values are updated concurrently.
+        pub fn sum_remote(&self) -> #ty #sum_bound {
+            <Self as percpu::PerCpu>::sum_remote(self)
+        }
+    };
+
+    // The accessors that reference the per-CPU data on the current CPU. With the `rseq` feature, preemption cannot be
+    // disabled in userspace, so they are only correct if no two threads access the data of a CPU at the same time (see
+    // the `rseq` section of the `PerCpu` documentation).
+    let current_methods = quote! {
+        /// Manipulate the per-CPU data on the current CPU in the given closure.
+        #[doc = #during_call_doc]
+        pub fn with_current<F, T>(&self, f: F) -> T
+        where
+            F: FnOnce(&mut #ty) -> T,
+        {
+            #default_guard
+            f(unsafe { self.current_ref_mut_raw() })
+        }
 
-    // Do not generate `fn read_current()`, `fn write_current()`, etc for non primitive types.
-    let read_write_methods = if is_primitive_int {
-        let read_current_raw = arch::gen_read_current_raw(inner_symbol_name, ty);
-        let write_current_raw =
-            arch::gen_write_current_raw(inner_symbol_name, &format_ident!("val"), ty);
+        /// Manipulate the per-CPU data on the current CPU in the given closure.
+        /// Local IRQs and preemption will be disabled during the call.
+        pub fn with_current_irqsave<F, T>(&self, f: F) -> T
+        where
+            F: FnOnce(&mut #ty) -> T,
+        {
+            #irq_save_guard
+            f(unsafe { self.current_ref_mut_raw() })
+        }
+
+        /// Returns the reference of the per-CPU data on the current CPU, while preemption is disabled as
+        /// proved by `token` (from `percpu::scope` or `percpu::CpuGuard`).
+        #[inline]
+        pub fn get<'a>(&'a self, _token: &'a percpu::NoPreemptToken) -> &'a #ty {
+            unsafe { self.current_ref_raw() }
+        }
+
+        /// Returns the mutable reference of the per-CPU data on the current CPU, while preemption is disabled
+        /// as proved by `token`.
+        ///
+        /// The token is borrowed mutably until the reference is dropped, so no other reference can be obtained
+        /// through it meanwhile.
+        ///
+        /// # Safety
+        ///
+        /// Caller must ensure that no reference to the per-CPU data on the current CPU that is not obtained
+        /// through `token` (e.g., through the token of a nested `percpu::scope` or another `percpu::CpuGuard`,
+        /// `with_current` or `current()`) is alive while the returned reference is.
+        #[inline]
+        #[allow(clippy::mut_from_ref)]
+        pub unsafe fn get_mut<'a>(&'a self, _token: &'a mut percpu::NoPreemptToken) -> &'a mut #ty {
+            self.current_ref_mut_raw()
+        }
+
+        /// Returns a guard that dereferences to the per-CPU data on the current CPU.
+        #[doc = #until_drop_doc]
+        #[inline]
+        pub fn current(&self) -> percpu::PerCpuRef<'_, #ty, #default_guard_ty> {
+            percpu::PerCpuRef::new(self)
+        }
+
+        /// Returns a guard that mutably dereferences to the per-CPU data on the current CPU.
+        #[doc = #until_drop_doc]
+        ///
+        /// # Safety
+        ///
+        /// Caller must ensure that no other reference to the per-CPU data on the current CPU (e.g., from another
+        /// `current_mut()`, `current()`, `with_current` or `get`) is alive while the guard is held.
+        #[inline]
+        pub unsafe fn current_mut(&self) -> percpu::PerCpuRefMut<'_, #ty, #default_guard_ty> {
+            percpu::PerCpuRefMut::new(self)
+        }
+
+        /// Returns a guard that dereferences to the per-CPU data on the current CPU.
+        /// Local IRQs and preemption will be disabled until the guard is dropped.
+        #[inline]
+        pub fn current_irqsave(&self) -> percpu::PerCpuRef<'_, #ty, percpu::__priv::IrqSaveGuard> {
+            percpu::PerCpuRef::new(self)
+        }
+
+        /// Returns a guard that mutably dereferences to the per-CPU data on the current CPU.
+        /// Local IRQs and preemption will be disabled until the guard is dropped.
+        ///
+        /// # Safety
+        ///
+        /// The same as `current_mut()`.
+        #[inline]
+        pub unsafe fn current_mut_irqsave(&self) -> percpu::PerCpuRefMut<'_, #ty, percpu::__priv::IrqSaveGuard> {
+            percpu::PerCpuRefMut::new(self)
+        }
+
+        /// Returns the value of the per-CPU static variable on the current CPU.
+        #[doc = #during_call_doc]
+        pub fn read_current(&self) -> #ty #clone_bound {
+            #default_guard
+            unsafe { self.read_current_raw() }
+        }
 
+        /// Set the value of the per-CPU static variable on the current CPU.
+        #[doc = #during_call_doc]
+        pub fn write_current(&self, val: #ty) {
+            #default_guard
+            unsafe { self.write_current_raw(val) }
+        }
+
+        /// Returns the value of the per-CPU static variable on the current CPU. Local IRQs and preemption will be
+        /// disabled during the call.
+        pub fn read_current_irqsave(&self) -> #ty #clone_bound {
+            #irq_save_guard
+            unsafe { self.read_current_raw() }
+        }
+
+        /// Set the value of the per-CPU static variable on the current CPU. Local IRQs and preemption will be
+        /// disabled during the call.
+        pub fn write_current_irqsave(&self, val: #ty) {
+            #irq_save_guard
+            unsafe { self.write_current_raw(val) }
+        }
+
+        /// Returns the value of the per-CPU static variable on the current CPU, while preemption is disabled as
+        /// proved by `token` (from `percpu::scope` or `percpu::CpuGuard`).
+        #[inline]
+        pub fn read(&self, _token: &percpu::NoPreemptToken) -> #ty #clone_bound {
+            unsafe { self.read_current_raw() }
+        }
+
+        /// Set the value of the per-CPU static variable on the current CPU, while preemption is disabled as proved
+        /// by `token`.
+        ///
+        /// # Safety
+        ///
+        /// The same as [`get_mut`](Self::get_mut).
+        #[inline]
+        pub unsafe fn write(&self, _token: &mut percpu::NoPreemptToken, val: #ty) {
+            self.write_current_raw(val)
+        }
+    };
+
+    let val = &format_ident!("val");
+    let (old, new) = (&format_ident!("old"), &format_ident!("new"));
+    let add_current = gen_match_size(ty, |repr| {
+        let add = arch::gen_add_current(inner_symbol_name, val, repr, false);
         quote! {
-            /// Returns the value of the per-CPU static variable on the current CPU.
-            ///
-            /// # Safety
-            ///
-            /// Caller must ensure that preemption is disabled on the current CPU.
-            #[inline]
-            pub unsafe fn read_current_raw(&self) -> #ty {
-                #read_current_raw
-            }
+            let val = bits as #repr;
+            #add
+        }
+    });
+    let fetch_add_current = gen_match_size(ty, |repr| {
+        let fetch_add = arch::gen_add_current(inner_symbol_name, val, repr, true);
+        quote! {
+            let val = bits as #repr;
+            let value: #repr = #fetch_add;
+            value as u64
+        }
+    });
+    let cmpxchg_current = gen_match_size(ty, |repr| {
+        let cmpxchg = arch::gen_cmpxchg_current(inner_symbol_name, old, new, repr);
+        quote! {
+            let (old, new) = (old_bits as #repr, new_bits as #repr);
+            let result: Result<#repr, #repr> = #cmpxchg;
+            result.map(|v| v as u64).map_err(|v| v as u64)
+        }
+    });
+    let xchg_current = gen_match_size(ty, |repr| {
+        let xchg = arch::gen_xchg_current(inner_symbol_name, new, repr);
+        quote! {
+            let new = bits as #repr;
+            let value: #repr = #xchg;
+            value as u64
+        }
+    });
+    let atomic_methods = quote! {
+        /// Adds `val` to the per-CPU static variable on the current CPU, wrapping around on overflow.
+        ///
+        /// The update is performed by a single instruction (or a single atomic operation) on the current CPU's
+        /// data, so it is safe against interrupts and does not need to disable preemption. x86_64 uses
+        /// `gs`-relative instructions, aarch64 uses LSE instructions or exclusive load/store loops, and riscv64 and
+        /// loongarch64 use AMO instructions or LL/SC loops for 32- and 64-bit types. 8- and 16-bit types on riscv64
+        /// and loongarch64 and other architectures fall back to an atomic operation through a pointer to the current
+        /// CPU's data.
+        #[inline]
+        pub fn add_current(&self, val: #ty) #integer_bound {
+            let bits = percpu::PerCpuPrimitive::into_bits(val);
+            unsafe { #add_current }
+        }
 
-            /// Set the value of the per-CPU static variable on the current CPU.
-            ///
-            /// # Safety
-            ///
-            /// Caller must ensure that preemption is disabled on the current CPU.
-            #[inline]
-            pub unsafe fn write_current_raw(&self, val: #ty) {
-                #write_current_raw
-            }
+        /// Subtracts `val` from the per-CPU static variable on the current CPU, wrapping around on overflow.
+        ///
+        /// See [`add_current`](Self::add_current) for the atomicity guarantees.
+        #[inline]
+        pub fn sub_current(&self, val: #ty) #integer_bound {
+            let bits = percpu::PerCpuPrimitive::into_bits(val).wrapping_neg();
+            self.add_current(unsafe { percpu::PerCpuPrimitive::from_bits(bits) })
+        }
 
-            /// Returns the value of the per-CPU static variable on the current CPU. Preemption will be disabled during
-            /// the call.
-            pub fn read_current(&self) -> #ty {
-                #no_preempt_guard
-                unsafe { self.read_current_raw() }
-            }
+        /// Adds `val` to the per-CPU static variable on the current CPU, wrapping around on overflow, and returns
+        /// the previous value.
+        ///
+        /// See [`add_current`](Self::add_current) for the atomicity guarantees.
+        #[inline]
+        pub fn fetch_add_current(&self, val: #ty) -> #ty #integer_bound {
+            let bits = percpu::PerCpuPrimitive::into_bits(val);
+            unsafe { percpu::PerCpuPrimitive::from_bits(#fetch_add_current) }
+        }
 
-            /// Set the value of the per-CPU static variable on the current CPU. Preemption will be disabled during the
-            /// call.
-            pub fn write_current(&self, val: #ty) {
-                #no_preempt_guard
-                unsafe { self.write_current_raw(val) }
+        /// Stores `new` into the per-CPU static variable on the current CPU if its value is equal to `old`.
+        ///
+        /// The return value is a result indicating whether the new value was written and containing the previous
+        /// value. On success this value is guaranteed to be equal to `old`.
+        ///
+        /// See [`add_current`](Self::add_current) for the atomicity guarantees.
+        #[inline]
+        pub fn cmpxchg_current(&self, old: #ty, new: #ty) -> Result<#ty, #ty> #integer_bound {
+            let old_bits = percpu::PerCpuPrimitive::into_bits(old);
+            let new_bits = percpu::PerCpuPrimitive::into_bits(new);
+            let result = unsafe { #cmpxchg_current };
+            unsafe {
+                match result {
+                    Ok(bits) => Ok(percpu::PerCpuPrimitive::from_bits(bits)),
+                    Err(bits) => Err(percpu::PerCpuPrimitive::from_bits(bits)),
+                }
             }
         }
 
-        // Todo: maybe add `(read|write)_remote(_raw)?` here?
-    } else {
-        quote! {}
+        /// Stores `new` into the per-CPU static variable on the current CPU, returning the previous value.
+        ///
+        /// See [`add_current`](Self::add_current) for the atomicity guarantees.
+        #[inline]
+        pub fn xchg_current(&self, new: #ty) -> #ty #integer_bound {
+            let bits = percpu::PerCpuPrimitive::into_bits(new);
+            unsafe { percpu::PerCpuPrimitive::from_bits(#xchg_current) }
+        }
+
+        /// Increments the per-CPU static variable on the current CPU by one, wrapping around on overflow.
+        #[inline]
+        pub fn inc_current(&self) #integer_bound {
+            self.add_current(unsafe { percpu::PerCpuPrimitive::from_bits(1) })
+        }
+
+        /// Decrements the per-CPU static variable on the current CPU by one, wrapping around on overflow.
+        #[inline]
+        pub fn dec_current(&self) #integer_bound {
+            self.sub_current(unsafe { percpu::PerCpuPrimitive::from_bits(1) })
+        }
+    };
+
+    // The trait methods that forward to `current_methods`.
+    let current_impl = quote! {
+        #[inline]
+        fn with_current<F, T>(&self, f: F) -> T
+        where
+            F: FnOnce(&mut #ty) -> T,
+        {
+            #struct_name::with_current(self, f)
+        }
+
+        #[inline]
+        fn read_current(&self) -> #ty #clone_bound {
+            #struct_name::read_current(self)
+        }
+
+        #[inline]
+        fn write_current(&self, val: #ty) {
+            #struct_name::write_current(self, val)
+        }
     };
 
     let offset = arch::gen_offset(inner_symbol_name);
     let current_ptr = arch::gen_current_ptr(inner_symbol_name, ty);
+    let track_current_read = gen_loom_access(quote! { self.current_ptr() }, false);
+    let track_current_write = gen_loom_access(quote! { self.current_ptr() }, true);
+    let track_remote_read = gen_loom_access(quote! { self.remote_ptr(cpu_id) }, false);
+    let track_remote_write = gen_loom_access(quote! { self.remote_ptr(cpu_id) }, true);
+    let layout_entry = gen_layout_entry(name, inner_symbol_name, ty, attrs);
     quote! {
         #[cfg_attr(not(target_os = "macos"), link_section = ".percpu")] // unimplemented on macos
         #(#attrs)*
         static mut #inner_symbol_name: #ty = #init_expr;
 
+        #layout_entry
+
         #[doc = concat!("Wrapper struct for the per-CPU data [`", stringify!(#name), "`]")]
         #[allow(non_camel_case_types)]
         #vis struct #struct_name {}
@@ -199,6 +838,7 @@ fn def_percpu_impl(attr: TokenStream, item: TokenStream) -> TokenStream {
             /// Caller must ensure that preemption is disabled on the current CPU.
             #[inline]
             pub unsafe fn current_ref_raw(&self) -> &#ty {
+                #track_current_read
                 &*self.current_ptr()
             }
 
@@ -210,18 +850,11 @@ fn def_percpu_impl(attr: TokenStream, item: TokenStream) -> TokenStream {
             #[inline]
             #[allow(clippy::mut_from_ref)]
             pub unsafe fn current_ref_mut_raw(&self) -> &mut #ty {
+                #track_current_write
                 &mut *(self.current_ptr() as *mut #ty)
             }
 
-            /// Manipulate the per-CPU data on the current CPU in the given closure.
-            /// Preemption will be disabled during the call.
-            pub fn with_current<F, T>(&self, f: F) -> T
-            where
-                F: FnOnce(&mut #ty) -> T,
-            {
-                #no_preempt_guard
-                f(unsafe { self.current_ref_mut_raw() })
-            }
+            #current_methods
 
             /// Returns the raw pointer of this per-CPU static variable on the given CPU.
             ///
@@ -232,6 +865,7 @@ fn def_percpu_impl(attr: TokenStream, item: TokenStream) -> TokenStream {
             /// - data races will not happen.
             #[inline]
             pub unsafe fn remote_ptr(&self, cpu_id: usize) -> *const #ty {
+                #cpu_id_check
                 let base = percpu::percpu_area_base(cpu_id);
                 let offset = #offset;
                 (base + offset) as *const #ty
@@ -246,6 +880,7 @@ fn def_percpu_impl(attr: TokenStream, item: TokenStream) -> TokenStream {
             /// - data races will not happen.
             #[inline]
             pub unsafe fn remote_ref_raw(&self, cpu_id: usize) -> &#ty {
+                #track_remote_read
                 &*self.remote_ptr(cpu_id)
             }
 
@@ -259,19 +894,107 @@ fn def_percpu_impl(attr: TokenStream, item: TokenStream) -> TokenStream {
             #[inline]
             #[allow(clippy::mut_from_ref)]
             pub unsafe fn remote_ref_mut_raw(&self, cpu_id: usize) -> &mut #ty {
+                #track_remote_write
                 &mut *(self.remote_ptr(cpu_id) as *mut #ty)
             }
 
+            /// Returns the reference of the per-CPU static variable on the given CPU, or `None` if `cpu_id` is not
+            /// less than `percpu_area_num()`.
+            ///
+            /// # Safety
+            ///
+            /// Caller must ensure that data races will not happen.
+            #[inline]
+            pub unsafe fn try_remote_ref(&self, cpu_id: usize) -> Option<&#ty> {
+                <Self as percpu::PerCpu>::try_remote_ref(self, cpu_id)
+            }
+
+            /// Returns the mutable reference of the per-CPU static variable on the given CPU, or `None` if `cpu_id`
+            /// is not less than `percpu_area_num()`.
+            ///
+            /// # Safety
+            ///
+            /// Caller must ensure that data races will not happen.
+            #[inline]
+            #[allow(clippy::mut_from_ref)]
+            pub unsafe fn try_remote_mut(&self, cpu_id: usize) -> Option<&mut #ty> {
+                <Self as percpu::PerCpu>::try_remote_mut(self, cpu_id)
+            }
+
+            /// Returns an iterator that yields `(cpu_id, &data)` of the per-CPU static variable on every CPU whose
+            /// area is initialized.
+            ///
+            /// # Safety
+            ///
+            /// Caller must ensure that the per-CPU data on any CPU is not modified while the iterator or the
+            /// references yielded by it are alive.
+            #[inline]
+            pub unsafe fn iter_remote(&self) -> percpu::RemoteIter<'_, Self> {
+                percpu::RemoteIter::new(self)
+            }
+
             #read_write_methods
+
+            #atomic_methods
+        }
+
+        impl percpu::PerCpu for #struct_name {
+            type Target = #ty;
+            type Guard = #default_guard_ty;
+
+            #[inline]
+            fn offset(&self) -> usize {
+                #struct_name::offset(self)
+            }
+
+            #[inline]
+            unsafe fn current_ptr(&self) -> *const #ty {
+                #struct_name::current_ptr(self)
+            }
+
+            #[inline]
+            unsafe fn remote_ptr(&self, cpu_id: usize) -> *const #ty {
+                #struct_name::remote_ptr(self, cpu_id)
+            }
+
+            #[inline]
+            unsafe fn read_current_raw(&self) -> #ty #clone_bound {
+                #struct_name::read_current_raw(self)
+            }
+
+            #[inline]
+            unsafe fn write_current_raw(&self, val: #ty) {
+                #struct_name::write_current_raw(self, val)
+            }
+
+            #current_impl
+
+            #[inline]
+            fn read_remote(&self, cpu_id: usize) -> #ty #clone_bound {
+                #struct_name::read_remote(self, cpu_id)
+            }
+
+            #[inline]
+            unsafe fn write_remote(&self, cpu_id: usize, val: #ty) {
+                #struct_name::write_remote(self, cpu_id, val)
+            }
         }
     }
     .into()
 }
 
 #[cfg(all(feature = "custom-tp", not(feature = "sp-naive")))]
-fn def_percpu_impl(_args: TokenStream, input: TokenStream) -> TokenStream {
+fn def_percpu_impl(args: TokenStream, input: TokenStream) -> TokenStream {
     use syn::parse_macro_input;
 
+    let args_span = proc_macro2::TokenStream::from(args.clone());
+    if parse_macro_input!(args as PerCpuArgs).irq_safe {
+        return compiler_error(Error::new_spanned(
+            args_span,
+            "`irq_safe` is not supported with the `custom-tp` feature, use the `*_irqsave` accessors instead",
+        ));
+    }
+
     let ItemStatic {
         attrs,
         vis,
@@ -283,10 +1006,13 @@ fn def_percpu_impl(_args: TokenStream, input: TokenStream) -> TokenStream {
         ..
     } = parse_macro_input!(input as ItemStatic);
 
+    let layout_entry = gen_layout_entry(&ident, &ident, &ty, &attrs);
     quote! {
         #[unsafe(link_section = ".percpu")]
         #(#attrs)*
         #vis #static_token #mutability #ident : ::percpu::PerCpuData<#ty> = ::percpu::PerCpuData::new(#expr);
+
+        #layout_entry
     }
     .into()
 }
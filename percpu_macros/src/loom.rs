@@ -0,0 +1,77 @@
+//! With the `loom` feature, the per-CPU data register is a thread-local variable of the current loom thread, so the
+//! data on the current CPU is accessed through the pointer, and every access is recorded by crate `percpu` for loom
+//! to check. Loom threads are not preempted in the middle of an operation, so the arithmetic operations need no
+//! special instructions.
+
+use quote::quote;
+use syn::{Ident, Type};
+
+#[allow(dead_code)]
+#[path = "arch.rs"]
+mod generic;
+
+pub use generic::gen_offset;
+
+pub fn gen_current_ptr(_symbol: &Ident, ty: &Type) -> proc_macro2::TokenStream {
+    quote! {
+        (percpu::read_percpu_reg() + self.offset()) as *const #ty
+    }
+}
+
+pub fn gen_read_current_raw(_symbol: &Ident, ty: &Type) -> proc_macro2::TokenStream {
+    let track = crate::gen_loom_access(quote! { self.current_ptr() }, false);
+    quote! {
+        {
+            #track
+            *(self.current_ptr() as *const #ty)
+        }
+    }
+}
+
+pub fn gen_write_current_raw(_symbol: &Ident, val: &Ident, ty: &Type) -> proc_macro2::TokenStream {
+    let track = crate::gen_loom_access(quote! { self.current_ptr() }, true);
+    quote! {
+        {
+            #track
+            *(self.current_ptr() as *mut #ty) = #val
+        }
+    }
+}
+
+/// Generate a code block that records a write of the per-CPU variable on the current CPU before `code`.
+fn gen_tracked_write(code: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    let track = crate::gen_loom_access(quote! { self.current_ptr() }, true);
+    quote! {
+        {
+            #track
+            #code
+        }
+    }
+}
+
+pub fn gen_add_current(
+    _symbol: &Ident,
+    val: &Ident,
+    ty: &Type,
+    fetch: bool,
+) -> proc_macro2::TokenStream {
+    let code = gen_tracked_write(crate::gen_atomic_fetch_add_current(val, ty));
+    if fetch {
+        code
+    } else {
+        quote! { #code; }
+    }
+}
+
+pub fn gen_cmpxchg_current(
+    _symbol: &Ident,
+    old: &Ident,
+    new: &Ident,
+    ty: &Type,
+) -> proc_macro2::TokenStream {
+    gen_tracked_write(crate::gen_atomic_cmpxchg_current(old, new, ty))
+}
+
+pub fn gen_xchg_current(_symbol: &Ident, new: &Ident, ty: &Type) -> proc_macro2::TokenStream {
+    gen_tracked_write(crate::gen_atomic_xchg_current(new, ty))
+}
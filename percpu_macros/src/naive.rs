@@ -15,9 +15,9 @@ pub fn gen_current_ptr(symbol: &Ident, _ty: &Type) -> proc_macro2::TokenStream {
     }
 }
 
-pub fn gen_read_current_raw(_symbol: &Ident, _ty: &Type) -> proc_macro2::TokenStream {
+pub fn gen_read_current_raw(_symbol: &Ident, ty: &Type) -> proc_macro2::TokenStream {
     quote! {
-        *self.current_ptr()
+        *(self.current_ptr() as *const #ty)
     }
 }
 
@@ -26,3 +26,30 @@ pub fn gen_write_current_raw(_symbol: &Ident, val: &Ident, ty: &Type) -> proc_ma
         *(self.current_ptr() as *mut #ty) = #val
     }
 }
+
+pub fn gen_add_current(
+    _symbol: &Ident,
+    val: &Ident,
+    ty: &Type,
+    fetch: bool,
+) -> proc_macro2::TokenStream {
+    let code = crate::gen_atomic_fetch_add_current(val, ty);
+    if fetch {
+        code
+    } else {
+        quote! { #code; }
+    }
+}
+
+pub fn gen_cmpxchg_current(
+    _symbol: &Ident,
+    old: &Ident,
+    new: &Ident,
+    ty: &Type,
+) -> proc_macro2::TokenStream {
+    crate::gen_atomic_cmpxchg_current(old, new, ty)
+}
+
+pub fn gen_xchg_current(_symbol: &Ident, new: &Ident, ty: &Type) -> proc_macro2::TokenStream {
+    crate::gen_atomic_xchg_current(new, ty)
+}
@@ -0,0 +1,54 @@
+//! For Linux userspace on x86_64, the per-CPU data area is selected by the CPU that the thread is running on, which
+//! is read from the rseq area. Accesses are not relative to a register, so the data is referenced through the pointer,
+//! and the primitive reads and writes and the arithmetic operations are rseq critical sections implemented in crate
+//! `percpu`.
+
+use quote::quote;
+use syn::{Ident, Type};
+
+#[allow(dead_code)]
+#[path = "arch.rs"]
+mod generic;
+
+pub use generic::gen_offset;
+
+pub fn gen_current_ptr(_symbol: &Ident, ty: &Type) -> proc_macro2::TokenStream {
+    quote! {
+        (percpu::__priv::current_area_base() + self.offset()) as *const #ty
+    }
+}
+
+pub fn gen_read_current_raw(_symbol: &Ident, ty: &Type) -> proc_macro2::TokenStream {
+    quote! { percpu::__priv::read_current::<#ty>(self.offset()) }
+}
+
+pub fn gen_write_current_raw(_symbol: &Ident, val: &Ident, ty: &Type) -> proc_macro2::TokenStream {
+    quote! { percpu::__priv::write_current::<#ty>(self.offset(), #val) }
+}
+
+pub fn gen_add_current(
+    _symbol: &Ident,
+    val: &Ident,
+    ty: &Type,
+    fetch: bool,
+) -> proc_macro2::TokenStream {
+    let code = quote! { percpu::__priv::fetch_add_current::<#ty>(self.offset(), #val) };
+    if fetch {
+        code
+    } else {
+        quote! { #code; }
+    }
+}
+
+pub fn gen_cmpxchg_current(
+    _symbol: &Ident,
+    old: &Ident,
+    new: &Ident,
+    ty: &Type,
+) -> proc_macro2::TokenStream {
+    quote! { percpu::__priv::cmpxchg_current::<#ty>(self.offset(), #old, #new) }
+}
+
+pub fn gen_xchg_current(_symbol: &Ident, new: &Ident, ty: &Type) -> proc_macro2::TokenStream {
+    crate::gen_atomic_xchg_current(new, ty)
+}